@@ -0,0 +1,434 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+const READ_CHUNK_SIZE: usize = 4096;
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 8 * 1024;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    pub idle_timeout: Option<Duration>,
+    pub max_requests_per_connection: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+        }
+    }
+}
+
+/*
+ * Reads whole HTTP requests off of a stream, buffering anything that was read past the end of
+ * the current request so it can be returned by the next call
+ */
+pub(crate) struct Connection<R: Read> {
+    stream: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> Connection<R> {
+    pub fn new(stream: R) -> Self {
+        Self {
+            stream,
+            buf: vec![],
+        }
+    }
+
+    /*
+     * True when nothing of a following request has been received yet
+     */
+    pub fn is_idle(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /*
+     * Blocks until the first bytes of the next request have been received
+     */
+    pub fn wait_for_request(&mut self) -> Result<()> {
+        if self.is_idle() {
+            self.fill()?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Reads the request line and headers up to the blank line, then either exactly
+     * `Content-Length` bytes of body or a `Transfer-Encoding: chunked` body. A client sending
+     * `Expect: 100-continue` is told to go on through `interim` before its body is read. The
+     * returned bytes can be handed straight to `Request::parse`
+     */
+    pub fn read_request(&mut self, limits: &Limits, interim: &mut impl Write) -> Result<Vec<u8>> {
+        let head_len = loop {
+            if let Some(idx) = find(&self.buf, b"\r\n\r\n") {
+                break idx + 4;
+            }
+            if self.buf.len() > limits.max_header_size {
+                return Err(Error::HeadersTooLarge);
+            }
+            self.fill()?;
+        };
+        if head_len > limits.max_header_size {
+            return Err(Error::HeadersTooLarge);
+        }
+
+        let head = &self.buf[..head_len];
+        let expects_continue = header_value(head, "Expect")?
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+        let content_length = header_value(head, "Content-Length")?;
+        if let Some(encoding) = header_value(head, "Transfer-Encoding")? {
+            // a body framed both ways could be split differently by a proxy in front of us
+            if content_length.is_some() {
+                return Err(Error::ParseError(
+                    "both Transfer-Encoding and Content-Length are set".into(),
+                ));
+            }
+            // without `chunked` last only closing the connection would end the body
+            let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+            if !last.eq_ignore_ascii_case("chunked") {
+                return Err(Error::ParseError(format!(
+                    "unsupported Transfer-Encoding `{encoding}`"
+                )));
+            }
+            if expects_continue && self.buf.len() == head_len {
+                interim.write_all(CONTINUE)?;
+            }
+            return self.read_chunked(head_len, limits);
+        }
+
+        let content_length = match content_length {
+            Some(value) => value
+                .parse()
+                .map_err(|_| Error::ParseError(format!("invalid Content-Length `{value}`")))?,
+            None => 0,
+        };
+        if content_length > limits.max_body_size {
+            return Err(Error::PayloadTooLarge);
+        }
+
+        let total = head_len + content_length;
+        if expects_continue && self.buf.len() < total {
+            interim.write_all(CONTINUE)?;
+        }
+        self.fill_to(total)?;
+        let rest = self.buf.split_off(total);
+        Ok(std::mem::replace(&mut self.buf, rest))
+    }
+
+    /*
+     * Decodes a chunked body and rebuilds the request as if it had been sent with a
+     * `Content-Length`, with any trailers appended to the headers
+     */
+    fn read_chunked(&mut self, head_len: usize, limits: &Limits) -> Result<Vec<u8>> {
+        let mut pos = head_len;
+        let mut body = vec![];
+        loop {
+            let line = self.read_line(&mut pos, limits.max_header_size)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| Error::ParseError(format!("invalid chunk size `{size}`")))?;
+            if size == 0 {
+                break;
+            }
+            // the size comes from the client, compare without adding to it
+            if size > limits.max_body_size - body.len() {
+                return Err(Error::PayloadTooLarge);
+            }
+            self.fill_to(pos + size + 2)?;
+            if &self.buf[pos + size..pos + size + 2] != b"\r\n" {
+                return Err(Error::ParseError("chunk is missing its CRLF".into()));
+            }
+            body.extend_from_slice(&self.buf[pos..pos + size]);
+            pos += size + 2;
+        }
+
+        let mut trailers = vec![];
+        let trailer_start = pos;
+        loop {
+            let line = self.read_line(&mut pos, limits.max_header_size)?;
+            if line.is_empty() {
+                break;
+            }
+            if pos - trailer_start > limits.max_header_size {
+                return Err(Error::HeadersTooLarge);
+            }
+            trailers.push(line);
+        }
+
+        let head = str::from_utf8(&self.buf[..head_len - 2])
+            .map_err(|_| Error::ParseError("HTTP headers are not UTF-8".into()))?;
+        let mut request = String::new();
+        for line in head.split_terminator("\r\n") {
+            let is_transfer_encoding = line
+                .split_once(':')
+                .is_some_and(|(header, _)| header.trim().eq_ignore_ascii_case("Transfer-Encoding"));
+            if !is_transfer_encoding {
+                request.push_str(line);
+                request.push_str("\r\n");
+            }
+        }
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        for trailer in trailers {
+            request.push_str(&trailer);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+
+        let mut request = request.into_bytes();
+        request.extend_from_slice(&body);
+        self.buf.drain(..pos);
+        Ok(request)
+    }
+
+    // reads a CRLF terminated line starting at `pos`, leaving `pos` just past the CRLF
+    fn read_line(&mut self, pos: &mut usize, max_len: usize) -> Result<String> {
+        let end = loop {
+            if let Some(idx) = find(&self.buf[*pos..], b"\r\n") {
+                break *pos + idx;
+            }
+            if self.buf.len() - *pos > max_len {
+                return Err(Error::HeadersTooLarge);
+            }
+            self.fill()?;
+        };
+        let line = str::from_utf8(&self.buf[*pos..end])
+            .map_err(|_| Error::ParseError("chunked body framing is not UTF-8".into()))?
+            .to_string();
+        *pos = end + 2;
+        Ok(line)
+    }
+
+    fn fill_to(&mut self, len: usize) -> Result<()> {
+        while self.buf.len() < len {
+            self.fill()?;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let read = self.stream.read(&mut chunk)?;
+        if read == 0 {
+            return Err(Error::ConnectionError(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn header_value<'a>(head: &'a [u8], name: &str) -> Result<Option<&'a str>> {
+    let head =
+        str::from_utf8(head).map_err(|_| Error::ParseError("HTTP headers are not UTF-8".into()))?;
+    Ok(head.split("\r\n").skip(1).find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Request;
+
+    #[test]
+    fn reads_request_without_body() {
+        let mut conn = Connection::new("GET / HTTP/1.1\r\nHost: a\r\n\r\n".as_bytes());
+        let read = conn
+            .read_request(&Limits::default(), &mut io::sink())
+            .unwrap();
+        assert_eq!(read, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    }
+
+    #[test]
+    fn reads_body_larger_than_one_read() {
+        let body = "x".repeat(100_000);
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let mut conn = Connection::new(raw.as_bytes());
+        let read = conn
+            .read_request(&Limits::default(), &mut io::sink())
+            .unwrap();
+        assert_eq!(read, raw.as_bytes());
+    }
+
+    #[test]
+    fn content_length_is_case_insensitive() {
+        let raw = "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert_eq!(
+            conn.read_request(&Limits::default(), &mut io::sink())
+                .unwrap(),
+            raw.as_bytes()
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_body() {
+        let limits = Limits {
+            max_body_size: 4,
+            ..Default::default()
+        };
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert!(matches!(
+            conn.read_request(&limits, &mut io::sink()),
+            Err(Error::PayloadTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        let limits = Limits {
+            max_header_size: 16,
+            ..Default::default()
+        };
+        let raw = "GET / HTTP/1.1\r\nX-Long: aaaaaaaaaaaaaaaa\r\n\r\n";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert!(matches!(
+            conn.read_request(&limits, &mut io::sink()),
+            Err(Error::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn keeps_pipelined_requests_for_next_read() {
+        let raw = "POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /b HTTP/1.1\r\n\r\n";
+        let mut conn = Connection::new(raw.as_bytes());
+        let first = conn
+            .read_request(&Limits::default(), &mut io::sink())
+            .unwrap();
+        assert_eq!(first, b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(!conn.is_idle());
+        let second = conn
+            .read_request(&Limits::default(), &mut io::sink())
+            .unwrap();
+        assert_eq!(second, b"GET /b HTTP/1.1\r\n\r\n");
+        assert!(conn.is_idle());
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let raw = "POST /upload HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+        let mut conn = Connection::new(raw.as_bytes());
+        let read = conn
+            .read_request(&Limits::default(), &mut io::sink())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(read).unwrap(),
+            "POST /upload HTTP/1.1\r\nHost: a\r\nContent-Length: 12\r\n\r\nhello, world"
+        );
+    }
+
+    #[test]
+    fn chunked_trailers_become_headers() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            2\r\nhi\r\n0\r\nX-Checksum: abc\r\n\r\n";
+        let mut conn = Connection::new(raw.as_bytes());
+        let read = conn
+            .read_request(&Limits::default(), &mut io::sink())
+            .unwrap();
+        let request = Request::parse(&read).unwrap();
+        assert_eq!(request.headers.get("X-Checksum"), Some("abc"));
+        assert_eq!(request.headers.get("Transfer-Encoding"), None);
+        assert_eq!(request.body, b"hi");
+    }
+
+    #[test]
+    fn rejects_oversized_chunked_body() {
+        let limits = Limits {
+            max_body_size: 4,
+            ..Default::default()
+        };
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert!(matches!(
+            conn.read_request(&limits, &mut io::sink()),
+            Err(Error::PayloadTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_huge_chunk_size_after_a_chunk() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            1\r\na\r\nffffffffffffffff\r\n";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert!(matches!(
+            conn.read_request(&Limits::default(), &mut io::sink()),
+            Err(Error::PayloadTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_ambiguous_framing() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 99\r\nTransfer-Encoding: chunked\r\n\r\n\
+            2\r\nhi\r\n0\r\n\r\n";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert!(matches!(
+            conn.read_request(&Limits::default(), &mut io::sink()),
+            Err(Error::ParseError(_))
+        ));
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\nhi";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert!(matches!(
+            conn.read_request(&Limits::default(), &mut io::sink()),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn answers_expect_continue_before_the_body() {
+        let raw = "PUT / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n";
+        // the body only arrives after the headers have been read
+        let mut conn = Connection::new(raw.as_bytes().chain("hi".as_bytes()));
+        let mut interim = vec![];
+        let read = conn.read_request(&Limits::default(), &mut interim).unwrap();
+        assert!(read.ends_with(b"\r\n\r\nhi"));
+        assert_eq!(interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        let raw = "POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 20\r\n\r\n";
+        let limits = Limits {
+            max_body_size: 4,
+            ..Default::default()
+        };
+        let mut conn = Connection::new(raw.as_bytes());
+        let mut interim = vec![];
+        assert!(conn.read_request(&limits, &mut interim).is_err());
+        assert!(interim.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_body() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello";
+        let mut conn = Connection::new(raw.as_bytes());
+        assert!(matches!(
+            conn.read_request(&Limits::default(), &mut io::sink()),
+            Err(Error::ConnectionError(_))
+        ));
+    }
+}
@@ -1,21 +1,32 @@
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use serde::Serialize;
+use serde::de::DeserializeOwned;
 
+use crate::Flygplan;
 use crate::error::{Error, Result};
-use crate::http::{Params, Request, Response, Status};
+use crate::http::{ChunkedWriter, Method, Params, Request, Response, Status};
+use crate::query::QueryDeserializer;
 
-pub type Handler = Rc<dyn Fn(Context) -> Result<Context>>;
+pub type Handler = Arc<dyn Fn(Context) -> Result<Context> + Send + Sync>;
+pub type ErrorHandler = Arc<dyn Fn(Error, Context) -> Result<Context> + Send + Sync>;
 
 pub struct Context<'a> {
     pub request: Request<'a>,
     pub response: Response<'a>,
     url_params: Params<'a>,
     status_handlers: &'a Vec<(Status, Handler)>,
+    scoped_status_handlers: Vec<&'a [(Status, Handler)]>,
+    server: Option<&'a Flygplan<'a>>,
     stream: TcpStream,
+    // shared with the contexts answering errors for the same request
+    written: Arc<AtomicBool>,
 }
 
 impl<'a> Context<'a> {
@@ -30,18 +41,114 @@ impl<'a> Context<'a> {
             response: Response::default(),
             url_params,
             status_handlers,
+            scoped_status_handlers: vec![],
+            server: None,
             stream,
+            written: Arc::default(),
         }
     }
 
+    pub(crate) fn with_server(mut self, server: &'a Flygplan<'a>) -> Self {
+        self.server = Some(server);
+        self
+    }
+
+    pub(crate) fn set_url_params(&mut self, url_params: Params<'a>) {
+        self.url_params = url_params;
+    }
+
+    /*
+     * Hands the request over to the router of the server, where pre-routing middleware ends
+     */
+    pub(crate) fn route(self) -> Result<Self> {
+        match self.server {
+            Some(server) => server.dispatch(self),
+            None => self.status(Status::NotFound404),
+        }
+    }
+
+    /*
+     * Status handlers of a mounted server, looked up before the ones passed to `new` and in the
+     * order they were added
+     */
+    pub(crate) fn with_status_handlers(mut self, handlers: &'a [(Status, Handler)]) -> Self {
+        self.scoped_status_handlers.push(handlers);
+        self
+    }
+
+    pub(crate) fn try_clone_stream(&self) -> Result<TcpStream> {
+        self.stream.try_clone().map_err(Error::ConnectionError)
+    }
+
+    /*
+     * Whether a response has been written to the stream
+     */
+    pub fn is_written(&self) -> bool {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn written_flag(&self) -> Arc<AtomicBool> {
+        self.written.clone()
+    }
+
+    // answers the same request as the context `written` was taken from
+    pub(crate) fn with_written_flag(mut self, written: Arc<AtomicBool>) -> Self {
+        self.written = written;
+        self
+    }
+
     pub fn path_param(&self, key: &str) -> Option<String> {
         self.url_params.get(key)
     }
 
+    /*
+     * What the last wildcard of the route matched, segments matched by `**` are joined with `/`
+     */
+    pub fn wildcard(&self) -> Option<String> {
+        self.url_params.get("*")
+    }
+
+    /*
+     * Parses a path parameter into `T`, a missing or unparsable value is answered with a 400
+     */
+    pub fn path_param_as<T: FromStr>(&self, key: &str) -> Result<T> {
+        let value = self.path_param(key).ok_or(Error::http(
+            Status::BadRequest400,
+            format!("missing path parameter `{key}`"),
+        ))?;
+        value.parse().map_err(|_| {
+            Error::http(
+                Status::BadRequest400,
+                format!("invalid path parameter `{key}`: `{value}`"),
+            )
+        })
+    }
+
     pub fn query_param(&self, key: &str) -> Option<String> {
         self.request.resource.query_params.get(key)
     }
 
+    /*
+     * Deserializes the whole query into `T`, repeated keys fill `Vec` fields and empty or missing
+     * keys leave `Option` fields as `None`. A `Vec` field whose key may be missing needs
+     * `#[serde(default)]`, or can be an `Option<Vec<_>>`. A query that does not fit is answered
+     * with a 400 naming the offending field
+     */
+    pub fn query<T: DeserializeOwned>(&self) -> Result<T> {
+        T::deserialize(QueryDeserializer::new(&self.request.resource.query_params)).map_err(|err| {
+            Error::http(Status::BadRequest400, format!("invalid query, {err}")).with_source(err)
+        })
+    }
+
+    /*
+     * Builds the path of a named route, see `Flygplan::url_for`
+     */
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        self.server
+            .ok_or(Error::UrlError("no routes to build urls from".into()))?
+            .url_for(name, params)
+    }
+
     pub fn string(mut self, body: &str) -> Result<Self> {
         self.response.body = body.to_string();
         self.write()
@@ -50,17 +157,49 @@ impl<'a> Context<'a> {
     pub fn file(mut self, path: &str) -> Result<Self> {
         let mut file = File::open(path).map_err(Error::ConnectionError)?;
         let mut body = vec![];
-        file.read_to_end(&mut body).expect("failed to open file");
-        self.response.body = String::from_utf8(body).expect("response file is not UTF-8 encoded");
+        file.read_to_end(&mut body)
+            .map_err(Error::ConnectionError)?;
+        self.response.body = String::from_utf8(body)
+            .map_err(|_| Error::ParseError(format!("response file `{path}` is not UTF-8")))?;
+        self.write()
+    }
+
+    pub fn json<S: Serialize>(mut self, value: S) -> Result<Self> {
+        self.response.body =
+            serde_json::to_string(&value).map_err(|_| Error::SerializationError)?;
+        self.response
+            .headers
+            .set("Content-Type", "application/json");
         self.write()
     }
 
-    pub fn json<S: Serialize>(self, value: S) -> Result<Self> {
-        serde_json::to_writer(&self.stream, &value).map_err(|_| Error::SerializationError)?;
+    /*
+     * Streams a response whose length is not known upfront using `Transfer-Encoding: chunked`,
+     * anything already in the response body is sent as the first chunk
+     */
+    pub fn chunked<F: FnOnce(&mut ChunkedWriter<&TcpStream>) -> Result<()>>(
+        mut self,
+        write_body: F,
+    ) -> Result<Self> {
+        let body = std::mem::take(&mut self.response.body);
+        self.response.headers.set("Transfer-Encoding", "chunked");
+        (&self.stream)
+            .write_all(self.response.to_string().as_bytes())
+            .map_err(Error::ConnectionError)?;
+        self.written.store(true, Ordering::SeqCst);
+        if self.request.method == Method::Head {
+            return Ok(self);
+        }
+        let mut writer = ChunkedWriter::new(&self.stream);
+        writer
+            .write_all(body.as_bytes())
+            .map_err(Error::ConnectionError)?;
+        write_body(&mut writer)?;
+        writer.finish().map_err(Error::ConnectionError)?;
         Ok(self)
     }
 
-    pub fn redirect(mut self, route: &'a str) -> Result<Self> {
+    pub fn redirect(mut self, route: impl Into<Cow<'a, str>>) -> Result<Self> {
         self.response.status = Status::SeeOther303;
         self.response.headers.set("Location", route);
         self.write()
@@ -69,12 +208,17 @@ impl<'a> Context<'a> {
     /*
      * Respond with a generic HTTP response status handler
      */
-    pub fn status(self, status: Status) -> Result<Self> {
-        if let Some((_, handler)) = self
-            .status_handlers
+    pub fn status(mut self, status: Status) -> Result<Self> {
+        self.response.status = status;
+        let handler = self
+            .scoped_status_handlers
             .iter()
+            .copied()
+            .chain([self.status_handlers.as_slice()])
+            .flatten()
             .find(|(req_status, _)| *req_status == status)
-        {
+            .map(|(_, handler)| handler.clone());
+        if let Some(handler) = handler {
             (handler)(self)
         } else {
             self.string(&status.to_string())
@@ -82,10 +226,26 @@ impl<'a> Context<'a> {
     }
 
     pub fn write(mut self) -> Result<Self> {
-        let response = self.response.to_string();
+        let allows_body = self.response.status.allows_body();
+        if allows_body && self.response.headers.get("Content-Length").is_none() {
+            let length = self.response.body.len().to_string();
+            self.response.headers.set("Content-Length", length);
+        }
+        // HEAD responses carry the headers of the GET response, but never a body
+        let response = if self.request.method == Method::Head || !allows_body {
+            Response {
+                status: self.response.status,
+                headers: self.response.headers.clone(),
+                body: String::new(),
+            }
+            .to_string()
+        } else {
+            self.response.to_string()
+        };
         self.stream
-            .write(response.as_bytes())
+            .write_all(response.as_bytes())
             .map_err(Error::ConnectionError)?;
+        self.written.store(true, Ordering::SeqCst);
         Ok(self)
     }
 }
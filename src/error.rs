@@ -2,6 +2,8 @@ use std::io;
 
 use thiserror::Error;
 
+use crate::http::Status;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Connection error")]
@@ -10,6 +12,63 @@ pub enum Error {
     SerializationError,
     #[error("Parse error {0}")]
     ParseError(String),
+    #[error("Request headers are too large")]
+    HeadersTooLarge,
+    #[error("Request body is too large")]
+    PayloadTooLarge,
+    #[error("Url error {0}")]
+    UrlError(String),
+    #[error("Route conflict: {0}")]
+    RouteConflict(String),
+    #[error("Invalid route: {0}")]
+    InvalidRoute(String),
+    #[error("{status}: {message}")]
+    HttpError {
+        status: Status,
+        message: String,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+}
+
+impl Error {
+    /*
+     * An error that is answered with the given HTTP status, usable from handlers with `?`
+     */
+    pub fn http(status: Status, message: impl Into<String>) -> Self {
+        Self::HttpError {
+            status,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /*
+     * Attaches the underlying cause to an `HttpError`, other errors are returned unchanged
+     */
+    pub fn with_source(self, cause: impl std::error::Error + Send + Sync + 'static) -> Self {
+        match self {
+            Self::HttpError {
+                status, message, ..
+            } => Self::HttpError {
+                status,
+                message,
+                source: Some(Box::new(cause)),
+            },
+            err => err,
+        }
+    }
+
+    /*
+     * The HTTP status this error is answered with when nothing else handles it
+     */
+    pub fn status(&self) -> Status {
+        match self {
+            Self::HttpError { status, .. } => *status,
+            Self::HeadersTooLarge => Status::RequestHeaderFieldsTooLarge431,
+            Self::PayloadTooLarge => Status::PayloadTooLarge413,
+            _ => Status::InternalServerError500,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
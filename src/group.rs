@@ -0,0 +1,160 @@
+use std::sync::{Arc, Mutex};
+
+use crate::http::{Method, Status};
+use crate::middleware::Middleware;
+use crate::{Context, ErrorHandler, Flygplan, Handler, Result, Route};
+
+/*
+ * A set of routes sharing a path prefix and middleware, created with `Flygplan::group`, or
+ * served on one host only when created with `Flygplan::host`. Middleware used on a group only
+ * wraps the handlers of routes in that group and its subgroups
+ */
+pub struct Group<'f, 'a> {
+    flyg: &'f mut Flygplan<'a>,
+    prefix: String,
+    host: Option<String>,
+    scope: usize,
+}
+
+// the middleware of a group or mounted server, along with the scope it is nested in
+pub(crate) struct Scope {
+    pub parent: Option<usize>,
+    pub middlewares: Vec<Mutex<Box<dyn Middleware>>>,
+    pub status_handlers: Vec<(Status, Handler)>,
+    pub error_handler: Option<ErrorHandler>,
+}
+
+impl<'f, 'a> Group<'f, 'a> {
+    pub(crate) fn new(
+        flyg: &'f mut Flygplan<'a>,
+        prefix: String,
+        host: Option<String>,
+        parent: Option<usize>,
+    ) -> Self {
+        flyg.scopes.push(Scope {
+            parent,
+            middlewares: vec![],
+            status_handlers: vec![],
+            error_handler: None,
+        });
+        let scope = flyg.scopes.len() - 1;
+        Self {
+            flyg,
+            prefix,
+            host,
+            scope,
+        }
+    }
+
+    pub fn get<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Get], pattern, handler)
+    }
+
+    pub fn head<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Head], pattern, handler)
+    }
+
+    pub fn post<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Post], pattern, handler)
+    }
+
+    pub fn put<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Put], pattern, handler)
+    }
+
+    pub fn patch<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Patch], pattern, handler)
+    }
+
+    pub fn delete<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Delete], pattern, handler)
+    }
+
+    pub fn options<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Options], pattern, handler)
+    }
+
+    pub fn connect<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Connect], pattern, handler)
+    }
+
+    pub fn trace<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Trace], pattern, handler)
+    }
+
+    pub fn any<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[], pattern, handler)
+    }
+
+    pub fn add<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        methods: &[Method],
+        pattern: &str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        let mut route = Route::new(
+            methods.to_vec(),
+            format!("{}{}", self.prefix, pattern),
+            Arc::new(handler),
+        );
+        route.scope = Some(self.scope);
+        if let Some(host) = &self.host {
+            route.set_host(host);
+        }
+        self.flyg.router.add(route)
+    }
+
+    /*
+     * Creates a subgroup whose prefix and middleware are added on top of this group's
+     */
+    pub fn group(&mut self, prefix: &str) -> Group<'_, 'a> {
+        let prefix = format!("{}{}", self.prefix, prefix);
+        Group::new(self.flyg, prefix, self.host.clone(), Some(self.scope))
+    }
+
+    pub fn use_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.flyg.scopes[self.scope]
+            .middlewares
+            .push(Mutex::new(Box::new(middleware)));
+    }
+}
@@ -1,66 +1,319 @@
+pub mod connection;
 pub mod context;
 pub mod error;
+pub mod group;
 pub mod http;
 pub mod middleware;
-pub use crate::context::{Context, Handler};
+mod query;
+pub mod router;
+pub mod shutdown;
+use crate::connection::Connection;
+pub use crate::connection::Limits;
+pub use crate::context::{Context, ErrorHandler, Handler};
 pub use crate::error::{Error, Result};
+pub use crate::group::Group;
+use crate::group::Scope;
 use crate::middleware::Middleware;
+use crate::router::Router;
+pub use crate::router::{Route, RouteInfo, TrailingSlash};
+pub use crate::shutdown::ShutdownHandle;
 
 use crate::http::{Method, Params, Request, Status};
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::vec;
-use std::{
-    io::Read,
-    net::{TcpListener, TcpStream, ToSocketAddrs},
-};
-
-#[derive(Default)]
+use std::fmt::Display;
+use std::io;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::num::NonZero;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+use std::{thread, vec};
+
 pub struct Flygplan<'a> {
-    routes: Vec<Route<'a>>,
+    router: Router<'a>,
     status_handlers: Vec<(Status, Handler)>,
-    middlewares: Vec<RefCell<Box<dyn Middleware>>>,
+    error_handler: Option<ErrorHandler>,
+    middlewares: Vec<Mutex<Box<dyn Middleware>>>,
+    pre_middlewares: Vec<Mutex<Box<dyn Middleware>>>,
+    scopes: Vec<Scope>,
+    mounts: Vec<(String, usize)>,
+    limits: Limits,
+    workers: usize,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl<'a> Default for Flygplan<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Flygplan<'a> {
     pub fn new() -> Self {
         Self {
-            routes: vec![],
+            router: Router::default(),
             status_handlers: vec![],
+            error_handler: None,
             middlewares: vec![],
+            pre_middlewares: vec![],
+            scopes: vec![],
+            mounts: vec![],
+            limits: Limits::default(),
+            workers: thread::available_parallelism().map_or(1, NonZero::get),
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
         }
     }
 
-    pub fn get<F: Fn(Context) -> Result<Context> + 'static>(
+    pub fn get<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Get], pattern, handler)
+    }
+
+    pub fn head<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Head], pattern, handler)
+    }
+
+    pub fn post<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Post], pattern, handler)
+    }
+
+    pub fn put<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
         &mut self,
         pattern: &'a str,
         handler: F,
     ) -> &mut Route<'a> {
-        let route = Route::new(Method::Get, pattern, Rc::new(handler));
-        self.routes.push(route);
-        self.routes.last_mut().unwrap()
+        self.add(&[Method::Put], pattern, handler)
     }
 
-    pub fn post<F: Fn(Context) -> Result<Context> + 'static>(
+    pub fn patch<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
         &mut self,
         pattern: &'a str,
         handler: F,
     ) -> &mut Route<'a> {
-        let route = Route::new(Method::Post, pattern, Rc::new(handler));
-        self.routes.push(route);
-        self.routes.last_mut().unwrap()
+        self.add(&[Method::Patch], pattern, handler)
     }
 
-    pub fn status_handler<F: Fn(Context) -> Result<Context> + 'static>(
+    pub fn delete<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Delete], pattern, handler)
+    }
+
+    pub fn options<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Options], pattern, handler)
+    }
+
+    pub fn connect<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Connect], pattern, handler)
+    }
+
+    pub fn trace<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[Method::Trace], pattern, handler)
+    }
+
+    /*
+     * Registers a route that matches every method, including extension methods
+     */
+    pub fn any<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.add(&[], pattern, handler)
+    }
+
+    /*
+     * Registers one route for several methods, an empty list of methods matches any method
+     */
+    pub fn add<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        methods: &[Method],
+        pattern: &'a str,
+        handler: F,
+    ) -> &mut Route<'a> {
+        self.router
+            .add(Route::new(methods.to_vec(), pattern, Arc::new(handler)))
+    }
+
+    pub fn status_handler<F: Fn(Context) -> Result<Context> + Send + Sync + 'static>(
         &mut self,
         status: Status,
         handler: F,
     ) {
-        self.status_handlers.push((status, Rc::new(handler)));
+        self.status_handlers.push((status, Arc::new(handler)));
+    }
+
+    /*
+     * Turns any error returned by a handler into a response. Without one, or when it fails
+     * itself, the status handler matching the error's status responds instead
+     */
+    pub fn error_handler<F: Fn(Error, Context) -> Result<Context> + Send + Sync + 'static>(
+        &mut self,
+        handler: F,
+    ) {
+        self.error_handler = Some(Arc::new(handler));
     }
 
     pub fn use_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
-        self.middlewares.push(RefCell::new(Box::new(middleware)));
+        self.middlewares.push(Mutex::new(Box::new(middleware)));
+    }
+
+    /*
+     * Middleware that runs before the route is looked up, so changes it makes to the request,
+     * like rewriting its path or method, decide which route runs
+     */
+    pub fn pre<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.pre_middlewares.push(Mutex::new(Box::new(middleware)));
+    }
+
+    /*
+     * Routes registered on the returned group share the prefix and the group's middleware
+     */
+    pub fn group(&mut self, prefix: &str) -> Group<'_, 'a> {
+        Group::new(self, prefix.to_string(), None, None)
+    }
+
+    /*
+     * Routes registered on the returned group only serve requests for the host. `*` matches
+     * any single label of the host and `:name` captures one as a path param, so
+     * `:tenant.example.com` serves every tenant. Routes for a host win over routes for any host,
+     * which only serve requests for the host that none of its routes match
+     */
+    pub fn host(&mut self, host: &str) -> Group<'_, 'a> {
+        Group::new(self, String::new(), Some(host.to_string()), None)
+    }
+
+    /*
+     * Serves the routes of another server under `prefix`. Its middleware and error handler only
+     * apply to its own routes and its status handlers also answer requests under the prefix that
+     * match no route. Its trailing slash policy, limits and workers are those of this server, and
+     * its pre-routing middleware cannot run since this server does the routing, so its routes
+     * fail validation when it has any
+     */
+    pub fn mount(&mut self, prefix: &str, server: Flygplan<'a>) {
+        let mount = self.scopes.len();
+        let scope = |idx: Option<usize>| Some(idx.map_or(mount, |idx| mount + 1 + idx));
+        let mut router = server.router;
+        if !server.pre_middlewares.is_empty() {
+            router.invalidate("mounted servers cannot have pre-routing middleware");
+        }
+        self.scopes.push(Scope {
+            parent: None,
+            middlewares: server.middlewares,
+            status_handlers: server.status_handlers,
+            error_handler: server.error_handler,
+        });
+        self.scopes
+            .extend(server.scopes.into_iter().map(|mut mounted| {
+                mounted.parent = scope(mounted.parent);
+                mounted
+            }));
+        self.router.mount(prefix, router, scope);
+        self.mounts.push((prefix.to_string(), mount));
+        self.mounts.extend(
+            server
+                .mounts
+                .into_iter()
+                .map(|(inner, idx)| (format!("{}{}", prefix, inner), mount + 1 + idx)),
+        );
+    }
+
+    /*
+     * Whether `/hello/` is routed to `/hello` and the other way around, routes only match paths
+     * exactly by default
+     */
+    pub fn trailing_slash(&mut self, policy: TrailingSlash) -> &mut Self {
+        self.router.trailing_slash = policy;
+        self
+    }
+
+    /*
+     * Requests with a header section larger than this are rejected with a 431
+     */
+    pub fn max_header_size(&mut self, bytes: usize) -> &mut Self {
+        self.limits.max_header_size = bytes;
+        self
+    }
+
+    /*
+     * Requests with a `Content-Length` larger than this are rejected with a 413
+     */
+    pub fn max_body_size(&mut self, bytes: usize) -> &mut Self {
+        self.limits.max_body_size = bytes;
+        self
+    }
+
+    /*
+     * How long a kept-alive connection may sit idle before it is closed, `None` waits forever.
+     * Idle connections are closed sooner when new connections are waiting for a worker
+     */
+    pub fn idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.limits.idle_timeout = timeout;
+        self
+    }
+
+    /*
+     * The connection is closed after serving this many requests
+     */
+    pub fn max_requests_per_connection(&mut self, requests: usize) -> &mut Self {
+        self.limits.max_requests_per_connection = requests.max(1);
+        self
+    }
+
+    /*
+     * Number of threads handling connections, defaults to the available parallelism
+     */
+    pub fn workers(&mut self, workers: usize) -> &mut Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /*
+     * How long in-flight requests get to finish after a shutdown before their connections are
+     * closed
+     */
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /*
+     * Returns a handle that stops `listen_and_serve` from another thread
+     */
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     pub fn listen_and_serve<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
@@ -68,181 +321,1270 @@ impl<'a> Flygplan<'a> {
         self.serve(listener)
     }
 
+    /*
+     * Builds the path of the route registered with `Route::name`, filling in its captures from
+     * `params`. Errors when there is no such route or a capture is missing
+     */
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        self.router.url_for(name, params)
+    }
+
+    /*
+     * Every registered route in the order it was registered, including the ones of mounted
+     * servers
+     */
+    pub fn routes(&self) -> impl Iterator<Item = RouteInfo<'_>> {
+        self.router.routes().map(|route| {
+            let mut middlewares = route.middlewares.len() + self.middlewares.len();
+            let mut scope = route.scope;
+            while let Some(idx) = scope {
+                middlewares += self.scopes[idx].middlewares.len();
+                scope = self.scopes[idx].parent;
+            }
+            route.info(middlewares)
+        })
+    }
+
+    /*
+     * Checks the route table for routes that shadow each other or share a name, this also runs
+     * before serving
+     */
+    pub fn validate(&self) -> Result<()> {
+        self.router.validate()
+    }
+
     fn serve(self, listener: TcpListener) -> Result<()> {
-        for c in listener.incoming() {
-            let mut stream = c.map_err(Error::ConnectionError)?;
-            let mut buf = [0u8; 2048];
-            stream.read(&mut buf).map_err(Error::ConnectionError)?;
-            let request = Request::parse(&buf).unwrap();
-            Self::handle_request(&self, stream, request);
+        self.validate()?;
+        let (sender, receiver) = mpsc::channel::<TcpStream>();
+        let receiver = Mutex::new(receiver);
+        // connections accepted but not yet picked up by a worker
+        let queued = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| {
+                    loop {
+                        // the lock is released as soon as a connection has been received
+                        let stream = match receiver.lock().unwrap().recv() {
+                            Ok(stream) => stream,
+                            Err(_) => return,
+                        };
+                        queued.fetch_sub(1, Ordering::SeqCst);
+                        // one connection going wrong, even by panicking, must not stop the worker
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                            self.handle_connection(stream, &queued)
+                        }));
+                    }
+                });
+            }
+            let accepted = self.accept_until_shutdown(&listener, &sender, &queued);
+            drop(sender);
+            let deadline = Instant::now() + self.shutdown_timeout;
+            while self.shutdown.has_connections() && Instant::now() < deadline {
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+            self.shutdown.close_all();
+            accepted
+        })
+    }
+
+    fn accept_until_shutdown(
+        &self,
+        listener: &TcpListener,
+        sender: &mpsc::Sender<TcpStream>,
+        queued: &AtomicUsize,
+    ) -> Result<()> {
+        self.shutdown.listen_on(listener)?;
+        while !self.shutdown.is_shutdown() {
+            match listener.accept() {
+                // the connection waking the loop up for a shutdown is dropped right away
+                Ok(_) if self.shutdown.is_shutdown() => break,
+                Ok((stream, _)) => {
+                    queued.fetch_add(1, Ordering::SeqCst);
+                    if sender.send(stream).is_err() {
+                        break;
+                    }
+                }
+                // one client or a moment without free file descriptors must not stop the server
+                Err(err) if is_transient(&err) => {
+                    if !matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::Interrupted
+                    ) {
+                        thread::sleep(ACCEPT_BACKOFF);
+                    }
+                }
+                Err(err) => {
+                    self.shutdown.shutdown();
+                    return Err(Error::ConnectionError(err));
+                }
+            }
         }
         Ok(())
     }
 
-    fn handle_request(&self, stream: TcpStream, request: Request) {
-        for route in self.routes.iter() {
-            if let Some(url_params) = route.matches(&request) {
-                let ctx = Context::new(request.clone(), url_params, &self.status_handlers, stream);
-                let handler = self
-                    .middlewares
-                    .iter()
-                    .fold(route.handler.clone(), |route, middleware| {
-                        middleware.borrow_mut().apply(route)
-                    });
-                let _err = handler(ctx).unwrap();
-                return;
+    /*
+     * Serves requests off of one connection until either side asks for it to be closed,
+     * HTTP/1.1 connections are kept alive by default
+     */
+    fn handle_connection(&self, stream: TcpStream, queued: &AtomicUsize) -> Result<()> {
+        stream.set_read_timeout(self.limits.idle_timeout)?;
+        let tracked = self.shutdown.track(&stream)?;
+        let mut conn = Connection::new(&stream);
+        let mut served = 0;
+        loop {
+            let waited = if served > 0 && conn.is_idle() {
+                self.wait_while_idle(&stream, &mut conn, queued)
+            } else {
+                conn.wait_for_request()
+            };
+            // the client hanging up or timing out between requests is a normal close
+            if waited.is_err() {
+                return Ok(());
+            }
+            // a shutdown lets a request that has started arriving finish
+            tracked.set_busy(true);
+            let buf = match conn.read_request(&self.limits, &mut &stream) {
+                Ok(buf) => buf,
+                Err(Error::HeadersTooLarge) => {
+                    return self.reject(&stream, Status::RequestHeaderFieldsTooLarge431);
+                }
+                Err(Error::PayloadTooLarge) => {
+                    return self.reject(&stream, Status::PayloadTooLarge413);
+                }
+                Err(Error::ParseError(_)) => return self.reject(&stream, Status::BadRequest400),
+                Err(err) => return Err(err),
+            };
+            served += 1;
+            let request = match Request::parse(&buf) {
+                Ok(request) => request,
+                Err(_) => return self.reject(&stream, Status::BadRequest400),
+            };
+            let keep_alive = served < self.limits.max_requests_per_connection
+                && !self.shutdown.is_shutdown()
+                && !request.headers.contains_token("Connection", "close");
+            if !self.handle_request(stream.try_clone()?, request, keep_alive)? {
+                return Ok(());
+            }
+            tracked.set_busy(!conn.is_idle());
+            if self.shutdown.is_shutdown() {
+                return Ok(());
             }
         }
-        Context::new(request, Params::default(), &self.status_handlers, stream)
-            .status(Status::NotFound404)
-            .unwrap();
     }
-}
 
-#[derive(Clone)]
-pub struct Route<'a> {
-    method: Method,
-    pattern: Vec<PatternSegment<'a>>,
-    handler: Handler,
-}
+    /*
+     * Waits for the next request on a kept-alive connection, giving up the worker as soon as
+     * other connections are waiting for one so that idle clients cannot hold every worker
+     */
+    fn wait_while_idle(
+        &self,
+        stream: &TcpStream,
+        conn: &mut Connection<&TcpStream>,
+        queued: &AtomicUsize,
+    ) -> Result<()> {
+        let deadline = self
+            .limits
+            .idle_timeout
+            .map(|timeout| Instant::now() + timeout);
+        stream.set_read_timeout(Some(IDLE_POLL_INTERVAL))?;
+        let waited = loop {
+            match conn.wait_for_request() {
+                Err(Error::ConnectionError(err))
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) && queued.load(Ordering::SeqCst) == 0
+                        && deadline.is_none_or(|deadline| Instant::now() < deadline) => {}
+                waited => break waited,
+            }
+        };
+        stream.set_read_timeout(self.limits.idle_timeout)?;
+        waited
+    }
 
-impl<'a> Route<'a> {
-    fn new(method: Method, pattern: &'a str, handler: Handler) -> Self {
-        Self {
-            method,
-            pattern: PatternSegment::parse(pattern),
-            handler,
-        }
+    /*
+     * Used when the request could not be read or parsed, so there is nothing to route. The
+     * status handlers still get a chance to respond before the connection is closed
+     */
+    fn reject(&self, stream: &TcpStream, status: Status) -> Result<()> {
+        let mut ctx = self.context(
+            Request::default(),
+            Params::default(),
+            None,
+            stream.try_clone()?,
+        );
+        ctx.response.headers.set("Connection", "close");
+        ctx.status(status)?;
+        Ok(())
     }
 
-    fn matches(&self, request: &'a Request) -> Option<Params<'a>> {
-        if request.method != self.method {
-            return None;
+    /*
+     * Returns whether the connection can be used for another request
+     */
+    fn handle_request(
+        &self,
+        stream: TcpStream,
+        request: Request,
+        keep_alive: bool,
+    ) -> Result<bool> {
+        let error_stream = stream.try_clone()?;
+        let mut ctx = self.context(request.clone(), Params::default(), None, stream);
+        let written = ctx.written_flag();
+        if !keep_alive {
+            ctx.response.headers.set("Connection", "close");
         }
-        let mut splits = request.resource.path.split("/").peekable();
-        let mut params = Params::new();
-        let mut pattern = self.pattern.iter().peekable();
-        while let Some(seg) = pattern.next() {
-            match seg {
-                PatternSegment::Static(s) => {
-                    if Some(*s) != splits.next() {
-                        return None;
-                    }
+        match self.pre_handler()(ctx) {
+            Ok(mut ctx) => {
+                // a handler that wrote nothing still answers, or keep-alive clients would wait
+                if !ctx.is_written() {
+                    ctx = ctx.write()?;
                 }
-                PatternSegment::Capture(s) => params.push((s, splits.next()?)),
-                PatternSegment::Wildcard => {
-                    splits.next();
+                Ok(keep_alive && !ctx.response.headers.contains_token("Connection", "close"))
+            }
+            // once a response has been written, closing the connection is all that is left
+            Err(_) if written.load(Ordering::SeqCst) => Ok(false),
+            Err(err) => {
+                // the request has not been routed when pre-routing middleware fails
+                let scope = self.mount_scope(&request.resource.path);
+                self.handle_error(err, request, scope, error_stream, written)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /*
+     * Routing wrapped in the pre-routing middleware, in the same order as `route_handler`
+     * wraps a route in the server wide middleware
+     */
+    fn pre_handler(&self) -> Handler {
+        let route: Handler = Arc::new(|ctx: Context| ctx.route());
+        self.pre_middlewares
+            .iter()
+            .fold(route, |handler, middleware| {
+                middleware.lock().unwrap().apply(handler)
+            })
+    }
+
+    /*
+     * Looks up the route of the request as the pre-routing middleware left it and runs it.
+     * Requests without a route are redirected to their trailing slash, answered with the
+     * methods that are allowed for the path or get a 404. Errors are answered here, where the
+     * scope of the request is known
+     */
+    pub(crate) fn dispatch<'r>(&'r self, ctx: Context<'r>) -> Result<Context<'r>> {
+        let request = ctx.request.clone();
+        let stream = ctx.try_clone_stream()?;
+        let written = ctx.written_flag();
+        let found = self.router.find(&request);
+        // the scope whose status handlers answer the request, going by its route or else its path
+        let scope = match &found {
+            Some((route, _)) => route.scope,
+            None => self.mount_scope(&request.resource.path),
+        };
+        let routed = self.run(self.in_scope(ctx, scope), found, &request);
+        routed.or_else(|err| self.handle_error(err, request, scope, stream, written))
+    }
+
+    // runs the route that was found, or answers a request that has none
+    fn run<'r>(
+        &'r self,
+        mut ctx: Context<'r>,
+        found: Option<(&Route, Params)>,
+        request: &Request,
+    ) -> Result<Context<'r>> {
+        match found {
+            Some((route, url_params)) => {
+                // routing ran on the encoded path so that `%2F` stays inside its segment
+                ctx.set_url_params(url_params.percent_decoded()?.into_owned());
+                self.route_handler(route)(ctx)
+            }
+            None if let Some(location) = self.router.trailing_slash_redirect(request) => {
+                ctx.response.status = Status::MovedPermanently301;
+                ctx.response.headers.set("Location", location);
+                ctx.write()
+            }
+            None => {
+                let allowed = self.router.allowed_methods(request);
+                if allowed.is_empty() {
+                    return ctx.status(Status::NotFound404);
                 }
-                PatternSegment::DoubleWildcard => {
-                    while let Some(part) = splits.peek() {
-                        if Some(&&PatternSegment::Static(part)) == pattern.peek() {
-                            break;
-                        }
-                        splits.next();
-                    }
+                let allow = allowed
+                    .iter()
+                    .map(Method::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                ctx.response.headers.set("Allow", allow);
+                if request.method == Method::Options {
+                    ctx.response.status = Status::NoContent204;
+                    ctx.write()
+                } else {
+                    ctx.status(Status::MethodNotAllowed405)
                 }
             }
         }
-        Some(params)
+    }
+
+    fn context<'r>(
+        &'r self,
+        request: Request<'r>,
+        url_params: Params<'r>,
+        scope: Option<usize>,
+        stream: TcpStream,
+    ) -> Context<'r> {
+        let ctx =
+            Context::new(request, url_params, &self.status_handlers, stream).with_server(self);
+        self.in_scope(ctx, scope)
+    }
+
+    /*
+     * Status handlers of the scopes the request was routed to take precedence over the ones
+     * registered on the server, innermost scope first
+     */
+    fn in_scope<'r>(&'r self, mut ctx: Context<'r>, mut scope: Option<usize>) -> Context<'r> {
+        while let Some(idx) = scope {
+            ctx = ctx.with_status_handlers(&self.scopes[idx].status_handlers);
+            scope = self.scopes[idx].parent;
+        }
+        ctx
+    }
+
+    // the scope of the innermost mounted server whose prefix contains the path
+    fn mount_scope(&self, path: &str) -> Option<usize> {
+        self.mounts
+            .iter()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+                })
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, scope)| *scope)
+    }
+
+    /*
+     * Wraps the route's handler in its own middleware, then the middleware of its groups,
+     * innermost group first, and then the middleware used on the whole server
+     */
+    fn route_handler(&self, route: &Route) -> Handler {
+        let apply = |handler, middleware: &Mutex<Box<dyn Middleware>>| {
+            middleware.lock().unwrap().apply(handler)
+        };
+        let mut handler = route
+            .middlewares
+            .iter()
+            .fold(route.handler.clone(), |handler, middleware| {
+                apply(handler, middleware)
+            });
+        let mut scope = route.scope;
+        while let Some(idx) = scope {
+            handler = self.scopes[idx].middlewares.iter().fold(handler, apply);
+            scope = self.scopes[idx].parent;
+        }
+        self.middlewares.iter().fold(handler, apply)
+    }
+
+    /*
+     * Responds to a request whose handler returned an error, the connection is closed afterwards
+     * since the handler may have already written part of a response. When it has, the error is
+     * returned as is, since a second response would only corrupt the first
+     */
+    fn handle_error<'r>(
+        &'r self,
+        mut err: Error,
+        request: Request<'r>,
+        scope: Option<usize>,
+        stream: TcpStream,
+        written: Arc<AtomicBool>,
+    ) -> Result<Context<'r>> {
+        if written.load(Ordering::SeqCst) {
+            return Err(err);
+        }
+        let new_context = || -> Result<Context> {
+            let mut ctx = self
+                .context(
+                    request.clone(),
+                    Params::default(),
+                    scope,
+                    stream.try_clone()?,
+                )
+                .with_written_flag(written.clone());
+            ctx.response.headers.set("Connection", "close");
+            Ok(ctx)
+        };
+        // the error handler of the innermost mounted server that has one
+        let error_handler = std::iter::successors(scope, |idx| self.scopes[*idx].parent)
+            .find_map(|idx| self.scopes[idx].error_handler.as_ref())
+            .or(self.error_handler.as_ref());
+        if let Some(error_handler) = error_handler {
+            match error_handler(err, new_context()?) {
+                Ok(ctx) => return Ok(ctx),
+                Err(handler_err) if written.load(Ordering::SeqCst) => return Err(handler_err),
+                Err(handler_err) => err = handler_err,
+            }
+        }
+        new_context()?.status(err.status())
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-enum PatternSegment<'a> {
-    Static(&'a str),
-    Capture(&'a str),
-    Wildcard,
-    DoubleWildcard,
+/*
+ * Whether an error from `accept` is over once the connection that caused it is gone or some
+ * resources have been freed, rather than the listener itself having failed
+ */
+fn is_transient(err: &io::Error) -> bool {
+    // EMFILE and ENFILE on unix, WSAEMFILE on windows
+    const FD_EXHAUSTION: [i32; 3] = [24, 23, 10024];
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::OutOfMemory
+    ) || err
+        .raw_os_error()
+        .is_some_and(|code| FD_EXHAUSTION.contains(&code))
 }
 
-impl<'a> PatternSegment<'a> {
-    fn parse(pattern: &'a str) -> Vec<PatternSegment<'a>> {
-        pattern
-            .split("/")
-            .map(|seg| match seg {
-                "*" => PatternSegment::Wildcard,
-                "**" => PatternSegment::DoubleWildcard,
-                seg if seg.starts_with(":") => PatternSegment::Capture(&seg[1..]),
-                seg => PatternSegment::Static(seg),
-            })
-            .collect()
+/*
+ * The route table, one aligned row per route in the order they were registered
+ */
+impl Display for Flygplan<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut rows = vec![[
+            "METHOD".to_string(),
+            "ROUTE".to_string(),
+            "NAME".to_string(),
+            "MIDDLEWARE".to_string(),
+        ]];
+        rows.extend(self.routes().map(|route| {
+            [
+                route.methods_string(),
+                format!("{}{}", route.host.unwrap_or_default(), route.pattern),
+                route.name.unwrap_or_default().to_string(),
+                route.middlewares.to_string(),
+            ]
+        }));
+        let mut widths = [0; 4];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in rows {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<Vec<_>>()
+                .join("  ");
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::http::{Headers, Method, Url};
+    use crate::http::Method;
+    use crate::middleware::RemoveTrailingSlash;
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
 
-    fn empty_handler() -> Handler {
-        return Rc::new(|c| Ok(c));
+    fn spawn_server(flyg: Flygplan<'static>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || flyg.serve(listener));
+        addr
     }
 
-    fn test_request<'a>(method: Method, path: &'a str) -> Request<'a> {
-        return Request {
-            method: method,
-            resource: Url::parse(path).unwrap(),
-            headers: Headers::new(),
-            body: &[],
-        };
+    fn send(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
     }
 
     #[test]
-    fn router_matches_single_slash() {
-        let route = Route::new(Method::Get, "/", empty_handler());
-        assert!(route.matches(&test_request(Method::Get, "/")).is_some());
+    fn workers_handle_slow_requests_in_parallel() {
+        let mut flyg = Flygplan::new();
+        flyg.workers(2);
+        flyg.get("/slow", |c| {
+            thread::sleep(Duration::from_millis(500));
+            c.string("done")
+        });
+        let addr = spawn_server(flyg);
+
+        let start = Instant::now();
+        let clients: Vec<_> = (0..2)
+            .map(|_| {
+                thread::spawn(move || send(addr, "GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n"))
+            })
+            .collect();
+        for client in clients {
+            assert!(client.join().unwrap().ends_with("done"));
+        }
+        assert!(start.elapsed() < Duration::from_millis(900));
     }
-    
+
     #[test]
-    fn router_matches_simple_path() {
-        let route = Route::new(Method::Get, "/hello/world", empty_handler());
-        assert!(route.matches(&test_request(Method::Get, "/hello/world")).is_some());
+    fn pipelined_requests_share_a_connection() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/a", |c| c.string("first"));
+        flyg.post("/b", |c| {
+            let body = String::from_utf8_lossy(c.request.body).to_string();
+            c.string(&body)
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "GET /a HTTP/1.1\r\n\r\n\
+            POST /b HTTP/1.1\r\nContent-Length: 6\r\nConnection: close\r\n\r\nsecond",
+        );
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst\
+            HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 6\r\n\r\nsecond"
+        );
     }
-    
+
+    #[test]
+    fn connection_closes_after_max_requests() {
+        let mut flyg = Flygplan::new();
+        flyg.max_requests_per_connection(1);
+        flyg.get("/", |c| c.string("hi"));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.contains("Connection: close"));
+        assert!(response.ends_with("hi"));
+    }
+
+    #[test]
+    fn idle_connection_times_out() {
+        let mut flyg = Flygplan::new();
+        flyg.idle_timeout(Some(Duration::from_millis(100)));
+        flyg.get("/", |c| c.string("hi"));
+        let addr = spawn_server(flyg);
+
+        let start = Instant::now();
+        let response = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("hi"));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn servers_start_and_stop_repeatedly() {
+        for _ in 0..3 {
+            let mut flyg = Flygplan::new();
+            flyg.get("/", |c| c.string("hi"));
+            let handle = flyg.shutdown_handle();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || flyg.serve(listener));
+
+            assert!(send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n").ends_with("hi"));
+            handle.shutdown();
+            assert!(server.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn shutdown_lets_in_flight_requests_finish() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/slow", |c| {
+            thread::sleep(Duration::from_millis(300));
+            c.string("done")
+        });
+        let handle = flyg.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || flyg.serve(listener));
+
+        let client = thread::spawn(move || send(addr, "GET /slow HTTP/1.1\r\n\r\n"));
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+        assert!(client.join().unwrap().ends_with("done"));
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections() {
+        let flyg = Flygplan::new();
+        let handle = flyg.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || flyg.serve(listener));
+
+        let _idle = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        handle.shutdown();
+        assert!(server.join().unwrap().is_ok());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
     #[test]
-    fn router_captures_dynamic_path() {
-        let route = Route::new(Method::Get, "/:id", empty_handler());
-        let request = test_request(Method::Get, "/1234");
-        let matched = route.matches(&request);
-        assert!(matched.is_some());
-        assert_eq!(matched.unwrap().get("id"), Some("1234".to_string()));
+    fn shutdown_closes_connections_still_waiting_for_a_worker() {
+        let mut flyg = Flygplan::new();
+        flyg.workers(1);
+        flyg.get("/slow", |c| {
+            thread::sleep(Duration::from_millis(300));
+            c.string("done")
+        });
+        let handle = flyg.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || flyg.serve(listener));
+
+        let client = thread::spawn(move || send(addr, "GET /slow HTTP/1.1\r\n\r\n"));
+        thread::sleep(Duration::from_millis(100));
+        let _queued = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        handle.shutdown();
+        assert!(client.join().unwrap().ends_with("done"));
+        assert!(server.join().unwrap().is_ok());
+        assert!(start.elapsed() < Duration::from_secs(1));
     }
-    
+
     #[test]
-    fn router_captures_multiple_dynamic_paths() {
-        let route = Route::new(Method::Get, "/:id/hello/:name", empty_handler());
-        let request = test_request(Method::Get, "/1234/hello/amelia");
-        let matched = route.matches(&request);
-        assert!(matched.is_some());
-        assert_eq!(matched.clone().unwrap().get("id"), Some("1234".to_string()));
-        assert_eq!(matched.clone().unwrap().get("name"), Some("amelia".to_string()));
+    fn shutdown_lets_partly_received_requests_finish() {
+        let mut flyg = Flygplan::new();
+        flyg.post("/", |c| {
+            let body = String::from_utf8_lossy(c.request.body).into_owned();
+            c.string(&body)
+        });
+        flyg.shutdown_timeout(Duration::from_secs(3));
+        let handle = flyg.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || flyg.serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"world").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("helloworld"));
+        assert!(server.join().unwrap().is_ok());
     }
-    
+
     #[test]
-    fn everything_matches_wildcard() {
-        let route = Route::new(Method::Get, "/*", empty_handler());
-        assert!(route.matches(&test_request(Method::Get, "/")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/hi")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/1234")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/trailingslash/")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/params/?key=val&key2=val2")).is_some());
+    fn shutdown_deadline_closes_slow_connections() {
+        let mut flyg = Flygplan::new();
+        flyg.shutdown_timeout(Duration::from_millis(300));
+        let handle = flyg.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || flyg.serve(listener));
+
+        // a request that never finishes sending its body keeps the connection busy reading
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhi")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        handle.shutdown();
+        assert!(server.join().unwrap().is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.is_empty());
     }
-    
+
     #[test]
-    fn everything_matches_double_wildcard() {
-        let route = Route::new(Method::Get, "/**", empty_handler());
-        assert!(route.matches(&test_request(Method::Get, "/")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/hello")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/hello/world")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/a/b/c/d/e/f/g/")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/hello?key=val&key2=val2")).is_some());
+    fn handlers_that_write_nothing_get_an_empty_response() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/noop", |c| Ok(c));
+        flyg.get("/", |c| c.string("hi"));
+        let addr = spawn_server(flyg);
+
+        let start = Instant::now();
+        let response = send(
+            addr,
+            "GET /noop HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Length: 0\r\n"));
+        assert!(response.ends_with("hi"));
+        assert!(start.elapsed() < Duration::from_secs(1));
     }
-    
+
+    #[test]
+    fn accept_errors_of_one_connection_are_transient() {
+        assert!(is_transient(&io::ErrorKind::ConnectionAborted.into()));
+        assert!(is_transient(&io::ErrorKind::Interrupted.into()));
+        assert!(is_transient(&io::Error::from_raw_os_error(24)));
+        assert!(!is_transient(&io::ErrorKind::InvalidInput.into()));
+    }
+
+    #[test]
+    fn idle_kept_alive_connections_give_up_their_worker() {
+        let mut flyg = Flygplan::new();
+        flyg.workers(1);
+        flyg.get("/", |c| c.string("hi"));
+        let addr = spawn_server(flyg);
+
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = [0; 512];
+        let read = idle.read(&mut response).unwrap();
+        assert!(String::from_utf8_lossy(&response[..read]).ends_with("hi"));
+
+        let start = Instant::now();
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("hi"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn malformed_request_gets_bad_request() {
+        let mut flyg = Flygplan::new();
+        flyg.status_handler(Status::BadRequest400, |c| c.string("bad request"));
+        flyg.get("/", |c| c.string("hi"));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "NONSENSE\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(response.ends_with("bad request"));
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("hi"));
+    }
+
+    #[test]
+    fn params_are_percent_decoded() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/hello/:name", |c| {
+            let name = c.path_param("name").unwrap();
+            let greeting = c.query_param("greeting").unwrap_or("hello".into());
+            c.string(&format!("{greeting}, {name}"))
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "GET /hello/Amelia%20S?greeting=good+day HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("good day, Amelia S"));
+
+        // an encoded slash does not split the segment
+        let response = send(
+            addr,
+            "GET /hello/a%2Fb HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("hello, a/b"));
+
+        let response = send(addr, "GET /hello/%zz HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[test]
+    fn handler_errors_get_internal_server_error() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/fails", |_| Err(Error::SerializationError));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /fails HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(response.contains("Connection: close"));
+    }
+
+    #[test]
+    fn handler_panics_do_not_stop_the_server() {
+        let mut flyg = Flygplan::new();
+        flyg.workers(1);
+        flyg.get("/panics", |_| panic!("handler panicked"));
+        flyg.get("/", |c| c.string("still here"));
+        let addr = spawn_server(flyg);
+
+        assert_eq!(send(addr, "GET /panics HTTP/1.1\r\n\r\n"), "");
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("still here"));
+    }
+
+    #[test]
+    fn http_errors_use_their_status_handler() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/admin", |_| {
+            Err(Error::http(Status::Forbidden403, "admins only"))
+        });
+        flyg.status_handler(Status::Forbidden403, |c| c.string("go away"));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /admin HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+        assert!(response.ends_with("go away"));
+    }
+
+    #[test]
+    fn error_handler_turns_errors_into_responses() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/admin", |_| {
+            Err(Error::http(Status::Forbidden403, "admins only"))
+        });
+        flyg.error_handler(|err, mut c| {
+            c.response.status = err.status();
+            let message = err.to_string();
+            c.string(&message)
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /admin HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+        assert!(response.ends_with("403 Forbidden: admins only"));
+    }
+
+    #[test]
+    fn errors_after_a_written_response_only_close_the_connection() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/", |c| {
+            c.string("first")?;
+            Err(Error::http(Status::Forbidden403, "too late"))
+        });
+        flyg.error_handler(|_, c| c.string("error"));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("first"));
+        assert_eq!(response.matches("HTTP/1.1").count(), 1);
+    }
+
+    #[test]
+    fn failing_error_handler_falls_back_to_status_handler() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/", |_| Err(Error::SerializationError));
+        flyg.error_handler(|err, _| Err(err));
+        flyg.status_handler(Status::InternalServerError500, |c| c.string("sorry"));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(response.ends_with("sorry"));
+    }
+
+    #[test]
+    fn wrong_method_gets_method_not_allowed() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/item", |c| c.string("item"));
+        flyg.add(&[Method::Put, Method::Delete], "/item", |c| {
+            c.string("changed")
+        });
+        flyg.status_handler(Status::MethodNotAllowed405, |c| {
+            c.string("try another method")
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "POST /item HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed"));
+        assert!(response.contains("Allow: GET, HEAD, PUT, DELETE, OPTIONS\r\n"));
+        assert!(response.ends_with("try another method"));
+
+        let response = send(addr, "POST /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn head_runs_get_handler_without_body() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/page", |c| c.string("hello"));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "HEAD /page HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\n"
+        );
+    }
+
     #[test]
-    fn wildcard_matches_with_other_patterns() {
-        let route = Route::new(Method::Get, "/hello/*/world", empty_handler());
-        assert!(route.matches(&test_request(Method::Get, "/hello/name/world")).is_some());
-        assert!(route.matches(&test_request(Method::Get, "/hello/12345/world")).is_some());
-        assert!(!route.matches(&test_request(Method::Get, "/hello/world")).is_some());
+    fn explicit_head_route_wins() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/page", |c| c.string("hello"));
+        flyg.head("/page", |mut c| {
+            c.response.headers.set("X-Head", "yes");
+            c.write()
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "HEAD /page HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.contains("X-Head: yes"));
+    }
+
+    #[test]
+    fn options_lists_registered_methods() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/item/:id", |c| c.string("item"));
+        flyg.patch("/item/:id", |c| c.string("patched"));
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "OPTIONS /item/1 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 204 No Content"));
+        assert!(response.contains("Allow: GET, HEAD, OPTIONS, PATCH\r\n"));
+        assert!(!response.contains("Content-Length"));
+    }
+
+    #[test]
+    fn conflicting_routes_stop_the_server_from_starting() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/users/:id", |c| c.string("by id"));
+        flyg.get("/users/:name", |c| c.string("by name"));
+        let err = flyg.listen_and_serve("127.0.0.1:0").unwrap_err();
+        assert!(matches!(err, Error::RouteConflict(_)));
+    }
+
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn apply(&mut self, handler: Handler) -> Handler {
+            let tag = self.0;
+            Arc::new(move |mut c: Context| -> Result<Context> {
+                c.response.headers.set("X-Tag", tag);
+                handler(c)
+            })
+        }
+    }
+
+    #[test]
+    fn group_routes_share_prefix_and_middleware() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/public", |c| c.string("public"));
+        let mut api = flyg.group("/api/v1");
+        api.use_middleware(Tag("api"));
+        api.get("/users", |c| c.string("users"));
+        let mut admin = api.group("/admin");
+        admin.use_middleware(Tag("admin"));
+        admin.delete("/users/:id", |c| {
+            let id = c.path_param("id").unwrap();
+            c.string(&format!("deleted {id}"))
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /public HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(!response.contains("X-Tag"));
+        assert!(response.ends_with("public"));
+
+        let response = send(
+            addr,
+            "GET /api/v1/users HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.contains("X-Tag: api\r\n"));
+        assert!(!response.contains("X-Tag: admin"));
+        assert!(response.ends_with("users"));
+
+        let response = send(
+            addr,
+            "DELETE /api/v1/admin/users/7 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.contains("X-Tag: api\r\nX-Tag: admin\r\n"));
+        assert!(response.ends_with("deleted 7"));
+    }
+
+    #[test]
+    fn mounted_servers_keep_their_middleware_and_status_handlers() {
+        let mut billing = Flygplan::new();
+        billing.use_middleware(Tag("billing"));
+        billing.status_handler(Status::NotFound404, |c| c.string("no such invoice"));
+        billing.get("/invoices/:id", |c| {
+            let id = c.path_param("id").unwrap();
+            c.string(&format!("invoice {id}"))
+        });
+        let mut flyg = Flygplan::new();
+        flyg.get("/", |c| c.string("home"));
+        flyg.status_handler(Status::NotFound404, |c| c.string("nothing here"));
+        flyg.mount("/billing", billing);
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "GET /billing/invoices/3 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.contains("X-Tag: billing\r\n"));
+        assert!(response.ends_with("invoice 3"));
+
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(!response.contains("X-Tag"));
+        assert!(response.ends_with("home"));
+
+        let response = send(
+            addr,
+            "GET /billing/unknown HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(response.ends_with("no such invoice"));
+
+        let response = send(addr, "GET /unknown HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("nothing here"));
+    }
+
+    #[test]
+    fn mounted_servers_keep_their_error_handler() {
+        let mut billing = Flygplan::new();
+        billing.get("/fail", |_| {
+            Err(Error::http(Status::Forbidden403, "locked"))
+        });
+        billing.error_handler(|err, mut c| {
+            c.response.status = err.status();
+            c.string("billing failed")
+        });
+        let mut flyg = Flygplan::new();
+        flyg.get("/fail", |_| {
+            Err(Error::http(Status::Forbidden403, "locked"))
+        });
+        flyg.error_handler(|_, c| c.string("server failed"));
+        flyg.mount("/billing", billing);
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /billing/fail HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403"));
+        assert!(response.ends_with("billing failed"));
+        let response = send(addr, "GET /fail HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("server failed"));
+    }
+
+    #[test]
+    fn mounted_servers_cannot_have_pre_routing_middleware() {
+        let mut billing = Flygplan::new();
+        billing.pre(Tag("billing"));
+        billing.get("/invoices", |c| Ok(c));
+        let mut flyg = Flygplan::new();
+        flyg.mount("/billing", billing);
+        assert_eq!(
+            flyg.validate().unwrap_err().to_string(),
+            "Invalid route: GET /billing/invoices: mounted servers cannot have pre-routing \
+             middleware"
+        );
+    }
+
+    #[test]
+    fn unparsable_typed_path_params_get_bad_request() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/orders/:id", |c| {
+            let id: u32 = c.path_param_as("id")?;
+            c.string(&format!("order {}", id + 1))
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /orders/41 HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("order 42"));
+
+        let response = send(addr, "GET /orders/x HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn queries_deserialize_into_structs() {
+        #[derive(serde::Deserialize)]
+        struct Page {
+            page: u32,
+            size: Option<u32>,
+            tag: Vec<String>,
+        }
+
+        let mut flyg = Flygplan::new();
+        flyg.get("/items", |c| {
+            let query: Page = c.query()?;
+            let body = format!("{} {:?} {}", query.page, query.size, query.tag.join(","));
+            c.string(&body)
+        });
+        flyg.error_handler(|err, mut c| {
+            c.response.status = err.status();
+            let message = err.to_string();
+            c.string(&message)
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "GET /items?page=2&tag=a&tag=b HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("2 None a,b"));
+
+        let response = send(
+            addr,
+            "GET /items?page=abc&tag=a HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(response.ends_with("field `page`: expected u32, found `abc`"));
+    }
+
+    #[test]
+    fn handlers_read_what_wildcards_matched() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/static/**", |c| {
+            let file = c.wildcard().unwrap();
+            c.string(&format!("serving {file}"))
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "GET /static/img/logo.png HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("serving img/logo.png"));
+    }
+
+    #[test]
+    fn trailing_slash_redirects_to_the_route() {
+        let mut flyg = Flygplan::new();
+        flyg.trailing_slash(TrailingSlash::Redirect);
+        flyg.get("/hello", |c| c.string("hello"));
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "GET /hello/?name=sam HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently"));
+        assert!(response.contains("Location: /hello?name=sam\r\n"));
+    }
+
+    #[test]
+    fn pre_middleware_rewrites_requests_before_routing() {
+        struct MethodOverride;
+        impl Middleware for MethodOverride {
+            fn apply(&mut self, handler: Handler) -> Handler {
+                Arc::new(move |mut c: Context| {
+                    if let Some(method) = c.request.headers.get("X-HTTP-Method-Override") {
+                        c.request.method = Method::try_from(method)?;
+                    }
+                    handler(c)
+                })
+            }
+        }
+
+        let mut flyg = Flygplan::new();
+        flyg.pre(RemoveTrailingSlash {});
+        flyg.pre(MethodOverride);
+        flyg.get("/", |c| c.string("root"));
+        flyg.get("/hello", |c| c.string("hello"));
+        flyg.delete("/posts/:id", |c| {
+            let id = c.path_param("id").unwrap();
+            c.string(&format!("deleted {id}"))
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /hello/ HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("hello"));
+
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("root"));
+
+        let response = send(
+            addr,
+            "POST /posts/4/ HTTP/1.1\r\nX-HTTP-Method-Override: DELETE\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("deleted 4"));
+    }
+
+    #[test]
+    fn hosts_share_a_port() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/", |c| c.string("marketing"));
+        flyg.host("api.example.com").get("/", |c| c.string("api"));
+        flyg.host(":tenant.admin.example.com").get("/", |c| {
+            let tenant = c.path_param("tenant").unwrap();
+            c.string(&format!("admin for {tenant}"))
+        });
+        let addr = spawn_server(flyg);
+
+        let response = send(
+            addr,
+            "GET / HTTP/1.1\r\nHost: api.example.com\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("api"));
+
+        let response = send(
+            addr,
+            "GET / HTTP/1.1\r\nHost: acme.admin.example.com:8080\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("admin for acme"));
+
+        let response = send(
+            addr,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("marketing"));
+    }
+
+    #[test]
+    fn routes_lists_the_route_table() {
+        let mut flyg = Flygplan::new();
+        flyg.use_middleware(Tag("server"));
+        flyg.get("/", |c| c.string("home")).name("home");
+        let mut api = flyg.group("/api");
+        api.use_middleware(Tag("api"));
+        api.delete("/users/:id", |c| Ok(c)).with(Tag("audit"));
+        flyg.host("admin.example.com").any("/**", |c| Ok(c));
+
+        let routes = flyg.routes().collect::<Vec<_>>();
+        assert_eq!(routes.len(), 3);
+        assert_eq!(routes[0].methods, &[Method::Get]);
+        assert_eq!(routes[0].pattern, "/");
+        assert_eq!(routes[0].name, Some("home"));
+        assert_eq!(routes[0].middlewares, 1);
+        assert_eq!(routes[1].pattern, "/api/users/:id");
+        assert_eq!(routes[1].middlewares, 3);
+        assert_eq!(routes[2].host, Some("admin.example.com"));
+
+        assert_eq!(
+            flyg.to_string(),
+            "METHOD  ROUTE                 NAME  MIDDLEWARE\n\
+             GET     /                     home  1\n\
+             DELETE  /api/users/:id              3\n\
+             ANY     admin.example.com/**        1\n"
+        );
+    }
+
+    #[test]
+    fn route_middleware_only_wraps_its_route() {
+        let mut flyg = Flygplan::new();
+        flyg.use_middleware(Tag("server"));
+        flyg.post("/login", |c| c.string("logged in"))
+            .with(Tag("rate-limited"))
+            .name("login");
+        flyg.get("/", |c| c.string("home"));
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "POST /login HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.contains("X-Tag: server\r\nX-Tag: rate-limited\r\n"));
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(!response.contains("rate-limited"));
+    }
+
+    #[test]
+    fn handlers_redirect_to_named_routes() {
+        let mut flyg = Flygplan::new();
+        flyg.get("/users/:id", |c| c.string("user"))
+            .name("user.show");
+        flyg.get("/me", |c| {
+            let url = c.url_for("user.show", &[("id", "42")])?;
+            c.redirect(url)
+        });
+        assert_eq!(
+            flyg.url_for("user.show", &[("id", "7")]).unwrap(),
+            "/users/7"
+        );
+        let addr = spawn_server(flyg);
+
+        let response = send(addr, "GET /me HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 303 See Other"));
+        assert!(response.contains("Location: /users/42\r\n"));
     }
 }
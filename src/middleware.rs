@@ -1,8 +1,8 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::{Context, Handler, error::Result};
 
-pub trait Middleware {
+pub trait Middleware: Send {
     fn apply(&mut self, handler: Handler) -> Handler;
 }
 
@@ -10,7 +10,7 @@ pub struct Logger {}
 
 impl Middleware for Logger {
     fn apply(&mut self, handler: Handler) -> Handler {
-        Rc::new(move |mut c: Context| -> Result<Context> {
+        Arc::new(move |mut c: Context| -> Result<Context> {
             c = handler(c)?;
             println!(
                 "{} {} HTTP/1.1\t{}",
@@ -25,7 +25,7 @@ pub struct AddTrailingSlash {}
 
 impl Middleware for AddTrailingSlash {
     fn apply(&mut self, handler: Handler) -> Handler {
-        Rc::new(move |mut c: Context<'_>| -> Result<Context> {
+        Arc::new(move |mut c: Context<'_>| -> Result<Context> {
             if !c.request.resource.path.ends_with('/') {
                 c.request.resource.path.to_mut().push('/');
             }
@@ -38,14 +38,12 @@ pub struct RemoveTrailingSlash {}
 
 impl Middleware for RemoveTrailingSlash {
     fn apply(&mut self, handler: Handler) -> Handler {
-        Rc::new(move |mut c: Context| -> Result<Context> {
-            c.request.resource.path = c
-                .request
-                .resource
-                .path
-                .trim_end_matches('/')
-                .to_owned()
-                .into();
+        Arc::new(move |mut c: Context| -> Result<Context> {
+            let path = c.request.resource.path.trim_end_matches('/');
+            // the root path is nothing but its slash
+            if !path.is_empty() {
+                c.request.resource.path = path.to_owned().into();
+            }
             handler(c)
         })
     }
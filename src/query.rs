@@ -0,0 +1,350 @@
+use std::fmt::{self, Display};
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::forward_to_deserialize_any;
+
+use crate::http::Params;
+
+// deserializes a key sent more than once as its first value
+macro_rules! forward_to_first_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+                Value(self.0[0]).$method(visitor)
+            }
+        )*
+    };
+}
+
+// parses the value into the type the field has
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident($ty:ty),)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+                let value = self.0.parse::<$ty>().map_err(|_| {
+                    QueryError(format!("expected {}, found `{}`", stringify!($ty), self.0))
+                })?;
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+/*
+ * Deserializes the params of a query into a struct or map. Repeated keys fill `Vec` fields, an
+ * `Option` field is `None` when its key is missing or empty, and numbers and booleans are parsed
+ * from their text. Scalar fields take the first value of a repeated key, like `Params::get`.
+ * Serde only knows a missing key as missing, so a `Vec` field that may be sent no values at all,
+ * like a multi-select with nothing selected, needs `#[serde(default)]` or to be an `Option`
+ */
+pub(crate) struct QueryDeserializer<'p> {
+    fields: Vec<(&'p str, Vec<&'p str>)>,
+}
+
+impl<'p> QueryDeserializer<'p> {
+    pub fn new(params: &'p Params) -> Self {
+        let mut fields: Vec<(&str, Vec<&str>)> = vec![];
+        for (key, value) in params.iter() {
+            match fields.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, values)) => values.push(value),
+                None => fields.push((key, vec![value])),
+            }
+        }
+        Self { fields }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryError(String);
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl de::Error for QueryError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl<'de> de::Deserializer<'de> for QueryDeserializer<'_> {
+    type Error = QueryError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_map(Fields {
+            fields: self.fields.into_iter(),
+            value: None,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier
+        ignored_any
+    }
+}
+
+struct Fields<'p> {
+    fields: std::vec::IntoIter<(&'p str, Vec<&'p str>)>,
+    value: Option<(&'p str, Vec<&'p str>)>,
+}
+
+impl<'de> MapAccess<'de> for Fields<'_> {
+    type Error = QueryError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, QueryError> {
+        let Some((key, values)) = self.fields.next() else {
+            return Ok(None);
+        };
+        self.value = Some((key, values));
+        seed.deserialize(IntoDeserializer::<QueryError>::into_deserializer(key))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, QueryError> {
+        let (key, values) = self
+            .value
+            .take()
+            .ok_or(QueryError("value requested before its key".into()))?;
+        // name the field, serde only does so for missing fields
+        seed.deserialize(Values(values))
+            .map_err(|err| QueryError(format!("field `{key}`: {err}")))
+    }
+}
+
+// every value sent for one key, there is always at least one
+struct Values<'p>(Vec<&'p str>);
+
+impl<'de> de::Deserializer<'de> for Values<'_> {
+    type Error = QueryError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        Value(self.0[0]).deserialize_any(visitor)
+    }
+
+    // every value is passed on so that `Option<Vec<T>>` gets all of them
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        if self.0.iter().all(|value| value.is_empty()) {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_seq(Seq(self.0.into_iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        Value(self.0[0]).deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_first_value! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_i128 deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_u128 deserialize_f32 deserialize_f64 deserialize_char deserialize_str
+        deserialize_string deserialize_unit
+    }
+
+    forward_to_deserialize_any! {
+        bytes byte_buf unit_struct tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct Seq<'p>(std::vec::IntoIter<&'p str>);
+
+impl<'de> SeqAccess<'de> for Seq<'_> {
+    type Error = QueryError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, QueryError> {
+        match self.0.next() {
+            Some(value) => seed.deserialize(Value(value)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+// a single value, parsed into whatever type the field has
+struct Value<'p>(&'p str);
+
+impl<'de> de::Deserializer<'de> for Value<'_> {
+    type Error = QueryError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_str(self.0)
+    }
+
+    // blank form inputs are sent with an empty value
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        visitor.visit_enum(IntoDeserializer::<QueryError>::into_deserializer(self.0))
+    }
+
+    parse_value! {
+        deserialize_bool => visit_bool(bool),
+        deserialize_i8 => visit_i8(i8),
+        deserialize_i16 => visit_i16(i16),
+        deserialize_i32 => visit_i32(i32),
+        deserialize_i64 => visit_i64(i64),
+        deserialize_i128 => visit_i128(i128),
+        deserialize_u8 => visit_u8(u8),
+        deserialize_u16 => visit_u16(u16),
+        deserialize_u32 => visit_u32(u32),
+        deserialize_u64 => visit_u64(u64),
+        deserialize_u128 => visit_u128(u128),
+        deserialize_f32 => visit_f32(f32),
+        deserialize_f64 => visit_f64(f64),
+        deserialize_char => visit_char(char),
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Sort {
+        Newest,
+        Oldest,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        q: String,
+        page: u32,
+        exact: bool,
+        max_price: Option<f64>,
+        sort: Option<Sort>,
+        #[serde(default)]
+        tag: Vec<String>,
+    }
+
+    fn search(query: &str) -> Result<Search, QueryError> {
+        let params = Params::parse_query_params(query).unwrap();
+        Search::deserialize(QueryDeserializer::new(&params))
+    }
+
+    #[test]
+    fn deserializes_typed_fields() {
+        let parsed = search("q=red+shoes&page=2&exact=true&tag=sale&sort=oldest&tag=new").unwrap();
+        assert_eq!(
+            parsed,
+            Search {
+                q: "red shoes".into(),
+                page: 2,
+                exact: true,
+                max_price: None,
+                sort: Some(Sort::Oldest),
+                tag: vec!["sale".into(), "new".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn empty_and_missing_optional_fields_are_none() {
+        let parsed = search("q=&page=1&exact=false&max_price=").unwrap();
+        assert_eq!(parsed.q, "");
+        assert_eq!(parsed.max_price, None);
+        assert_eq!(parsed.sort, None);
+        assert!(parsed.tag.is_empty());
+        let parsed = search("q=a&page=1&exact=false&max_price=9.5").unwrap();
+        assert_eq!(parsed.max_price, Some(9.5));
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Filter {
+        colors: Option<Vec<String>>,
+        #[serde(default)]
+        sizes: Vec<u32>,
+    }
+
+    fn filter(query: &str) -> Result<Filter, QueryError> {
+        let params = Params::parse_query_params(query).unwrap();
+        Filter::deserialize(QueryDeserializer::new(&params))
+    }
+
+    #[test]
+    fn multi_selects_may_send_nothing() {
+        let parsed = filter("colors=red&sizes=38&colors=blue&sizes=40").unwrap();
+        assert_eq!(parsed.colors, Some(vec!["red".into(), "blue".into()]));
+        assert_eq!(parsed.sizes, vec![38, 40]);
+        let parsed = filter("").unwrap();
+        assert_eq!(parsed.colors, None);
+        assert!(parsed.sizes.is_empty());
+        let err = filter("sizes=38&sizes=xl").unwrap_err();
+        assert_eq!(err.to_string(), "field `sizes`: expected u32, found `xl`");
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let err = search("q=a&page=two&exact=true").unwrap_err();
+        assert_eq!(err.to_string(), "field `page`: expected u32, found `two`");
+        let err = search("q=a&exact=true").unwrap_err();
+        assert_eq!(err.to_string(), "missing field `page`");
+    }
+}
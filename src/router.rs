@@ -0,0 +1,1726 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use regex::Regex;
+
+use crate::context::Handler;
+use crate::error::{Error, Result};
+use crate::http::{Method, Params, Request, percent_encode};
+use crate::middleware::Middleware;
+
+#[derive(Clone)]
+pub struct Route<'a> {
+    methods: Vec<Method>,
+    path: Cow<'a, str>,
+    pattern: Vec<PatternSegment>,
+    // how many of the segments at the end of the pattern are optional captures
+    optional: usize,
+    // only requests for a matching host are routed here, any host when there is none
+    host: Option<HostPattern>,
+    pub(crate) handler: Handler,
+    pub(crate) scope: Option<usize>,
+    pub(crate) middlewares: Vec<Arc<Mutex<Box<dyn Middleware>>>>,
+    name: Option<String>,
+    // why the pattern could not be parsed, reported by `Router::validate`
+    invalid: Option<String>,
+}
+
+impl<'a> Route<'a> {
+    pub(crate) fn new(
+        methods: Vec<Method>,
+        pattern: impl Into<Cow<'a, str>>,
+        handler: Handler,
+    ) -> Self {
+        let path = pattern.into();
+        let ((pattern, optional), invalid) = match PatternSegment::parse(&path) {
+            Ok(parsed) => (parsed, None),
+            Err(err) => ((vec![], 0), Some(err)),
+        };
+        Self {
+            methods,
+            path,
+            pattern,
+            optional,
+            host: None,
+            handler,
+            scope: None,
+            middlewares: vec![],
+            name: None,
+            invalid,
+        }
+    }
+
+    /*
+     * Wraps only this route's handler in the middleware, inside of any group or server wide
+     * middleware
+     */
+    pub fn with<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middlewares
+            .push(Arc::new(Mutex::new(Box::new(middleware))));
+        self
+    }
+
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /*
+     * Describes the route for `Flygplan::routes`, `middlewares` counts everything wrapping it
+     */
+    pub(crate) fn info(&self, middlewares: usize) -> RouteInfo<'_> {
+        RouteInfo {
+            methods: &self.methods,
+            host: self.host.as_ref().map(|host| host.source.as_str()),
+            pattern: &self.path,
+            name: self.name.as_deref(),
+            middlewares,
+        }
+    }
+
+    pub(crate) fn set_host(&mut self, host: &str) {
+        match HostPattern::parse(host) {
+            Ok(host) => self.host = Some(host),
+            Err(err) => self.invalid = self.invalid.take().or(Some(err)),
+        }
+    }
+
+    // an empty list of methods matches any method
+    fn allows(&self, method: &Method) -> bool {
+        self.methods.is_empty() || self.methods.contains(method)
+    }
+
+    fn serves(&self, host: &str) -> bool {
+        self.host
+            .as_ref()
+            .is_none_or(|pattern| pattern.captures(host).is_some())
+    }
+
+    fn methods_string(&self) -> String {
+        self.info(0).methods_string()
+    }
+
+    // the host and path of the route, as used in error messages
+    fn location(&self) -> String {
+        match &self.host {
+            Some(host) => format!("{}{}", host.source, self.path),
+            None => self.path.to_string(),
+        }
+    }
+
+    fn overlaps(&self, other: &Route) -> bool {
+        let methods_overlap = self.methods.is_empty()
+            || other.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|method| other.methods.contains(method));
+        methods_overlap && self.host == other.host
+    }
+
+    /*
+     * Pairs the names of the captures and wildcards of the pattern with the values the router
+     * captured for them. The last wildcard is also available as `*`, named or not
+     */
+    fn params<'r>(&'r self, captured: &[&'r str]) -> Params<'r> {
+        let mut params = Params::new();
+        let last_wildcard = self.pattern.iter().rposition(PatternSegment::is_wildcard);
+        let dynamic = self
+            .pattern
+            .iter()
+            .enumerate()
+            .filter(|(_, seg)| !matches!(seg, PatternSegment::Static(_)));
+        for ((idx, seg), value) in dynamic.zip(captured) {
+            if let PatternSegment::Capture(name, _)
+            | PatternSegment::Wildcard(Some(name))
+            | PatternSegment::DoubleWildcard(Some(name)) = seg
+            {
+                params.push((name.as_str(), *value));
+            }
+            if Some(idx) == last_wildcard {
+                params.push(("*", *value));
+            }
+        }
+        params
+    }
+}
+
+/*
+ * A registered route as listed by `Flygplan::routes`. No methods means the route answers any
+ * method, and `middlewares` counts the route's own middleware along with that of its groups and
+ * the whole server
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteInfo<'r> {
+    pub methods: &'r [Method],
+    pub host: Option<&'r str>,
+    pub pattern: &'r str,
+    pub name: Option<&'r str>,
+    pub middlewares: usize,
+}
+
+impl RouteInfo<'_> {
+    pub fn methods_string(&self) -> String {
+        if self.methods.is_empty() {
+            return "ANY".into();
+        }
+        self.methods
+            .iter()
+            .map(Method::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PatternSegment {
+    Static(String),
+    Capture(String, Option<Constraint>),
+    Wildcard(Option<String>),
+    DoubleWildcard(Option<String>),
+}
+
+impl PatternSegment {
+    /*
+     * Also returns how many captures at the end of the pattern are optional, written as
+     * `:id?`. Optional captures anywhere else would make the pattern ambiguous
+     */
+    fn parse(pattern: &str) -> std::result::Result<(Vec<PatternSegment>, usize), String> {
+        let mut optional = 0;
+        let mut segments = vec![];
+        for seg in pattern.split("/") {
+            match seg.strip_suffix('?').filter(|seg| seg.starts_with(':')) {
+                Some(seg) => {
+                    optional += 1;
+                    segments.push(Self::parse_segment(seg)?);
+                }
+                None if optional > 0 => {
+                    return Err(format!("`{seg}` follows an optional segment"));
+                }
+                None => segments.push(Self::parse_segment(seg)?),
+            }
+        }
+        Ok((segments, optional))
+    }
+
+    fn parse_segment(seg: &str) -> std::result::Result<PatternSegment, String> {
+        match seg {
+            seg if seg.starts_with("**") => {
+                Ok(PatternSegment::DoubleWildcard(name_of_wildcard(&seg[2..])))
+            }
+            seg if seg.starts_with("*") => {
+                Ok(PatternSegment::Wildcard(name_of_wildcard(&seg[1..])))
+            }
+            seg if seg.starts_with(":") => match seg[1..].split_once('<') {
+                Some((name, constraint)) => {
+                    let constraint = constraint
+                        .strip_suffix('>')
+                        .ok_or(format!("`{seg}` is missing its closing `>`"))?;
+                    Ok(PatternSegment::Capture(
+                        name.to_string(),
+                        Some(Constraint::parse(constraint)?),
+                    ))
+                }
+                None => Ok(PatternSegment::Capture(seg[1..].to_string(), None)),
+            },
+            seg => Ok(PatternSegment::Static(seg.to_string())),
+        }
+    }
+
+    fn is_wildcard(&self) -> bool {
+        matches!(
+            self,
+            PatternSegment::Wildcard(_) | PatternSegment::DoubleWildcard(_)
+        )
+    }
+}
+
+// `*path` and `**path` capture what they match under the name after the stars
+fn name_of_wildcard(name: &str) -> Option<String> {
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/*
+ * Restricts the segments a capture matches, written after its name as in `:id<u64>`. The name
+ * of a primitive type only matches values that parse as that type, anything else is a regular
+ * expression that has to match the whole segment
+ */
+#[derive(Clone, Debug)]
+enum Constraint {
+    Type(String, fn(&str) -> bool),
+    Regex(Regex),
+}
+
+fn parses<T: FromStr>(value: &str) -> bool {
+    value.parse::<T>().is_ok()
+}
+
+impl Constraint {
+    fn parse(source: &str) -> std::result::Result<Self, String> {
+        let check: fn(&str) -> bool = match source {
+            "u8" => parses::<u8>,
+            "u16" => parses::<u16>,
+            "u32" => parses::<u32>,
+            "u64" => parses::<u64>,
+            "u128" => parses::<u128>,
+            "usize" => parses::<usize>,
+            "i8" => parses::<i8>,
+            "i16" => parses::<i16>,
+            "i32" => parses::<i32>,
+            "i64" => parses::<i64>,
+            "i128" => parses::<i128>,
+            "isize" => parses::<isize>,
+            "f32" => parses::<f32>,
+            "f64" => parses::<f64>,
+            "bool" => parses::<bool>,
+            _ => {
+                return Regex::new(&format!("^(?:{source})$"))
+                    .map(Constraint::Regex)
+                    .map_err(|err| format!("invalid constraint `{source}`: {err}"));
+            }
+        };
+        Ok(Constraint::Type(source.to_string(), check))
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Constraint::Type(_, check) => check(value),
+            Constraint::Regex(regex) => regex.is_match(value),
+        }
+    }
+
+    fn source(&self) -> &str {
+        match self {
+            Constraint::Type(source, _) => source,
+            // strip the anchors added in `parse`
+            Constraint::Regex(regex) => &regex.as_str()[4..regex.as_str().len() - 2],
+        }
+    }
+}
+
+impl PartialEq for Constraint {
+    fn eq(&self, other: &Self) -> bool {
+        self.source() == other.source()
+    }
+}
+
+/*
+ * The host a route is served on, matched label by label and ignoring case. `*` matches any
+ * single label and `:name` captures one into the params of the request, like `:tenant` in
+ * `:tenant.example.com`
+ */
+#[derive(Clone, Debug, PartialEq)]
+struct HostPattern {
+    source: String,
+    labels: Vec<PatternSegment>,
+}
+
+impl HostPattern {
+    fn parse(host: &str) -> std::result::Result<Self, String> {
+        let labels = host
+            .split('.')
+            .map(|label| match PatternSegment::parse_segment(label)? {
+                PatternSegment::DoubleWildcard(_) => {
+                    Err(format!("`{label}` cannot be used in host `{host}`"))
+                }
+                label => Ok(label),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Self {
+            source: host.to_string(),
+            labels,
+        })
+    }
+
+    // the labels captured out of the host, none when the host does not match
+    fn captures<'r>(&'r self, host: &'r str) -> Option<Vec<(&'r str, &'r str)>> {
+        // hosts are never percent-encoded, and their labels are not decoded like path params
+        if host.contains('%') {
+            return None;
+        }
+        let labels = host.split('.').collect::<Vec<_>>();
+        if labels.len() != self.labels.len() {
+            return None;
+        }
+        let mut captured = vec![];
+        for (pattern, label) in self.labels.iter().zip(labels) {
+            match pattern {
+                PatternSegment::Static(s) if s.eq_ignore_ascii_case(label) => {}
+                PatternSegment::Capture(name, constraint)
+                    if constraint.as_ref().is_none_or(|c| c.matches(label)) =>
+                {
+                    captured.push((name.as_str(), label));
+                }
+                PatternSegment::Wildcard(name) => {
+                    if let Some(name) = name {
+                        captured.push((name.as_str(), label));
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(captured)
+    }
+}
+
+// the host the request was sent to without its port, from an absolute url or the `Host` header
+fn request_host<'r>(request: &'r Request) -> &'r str {
+    let host = match request.resource.host.as_ref() {
+        "" => request.headers.get("Host").unwrap_or_default(),
+        host => host,
+    };
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    host.trim_end_matches('.')
+}
+
+/*
+ * What to do with a request whose path only matches a route once its trailing slash is added or
+ * removed, like `/hello/` for a route registered as `/hello`
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TrailingSlash {
+    // the request does not match
+    #[default]
+    Strict,
+    // the request is routed as if it had the slash of the route
+    Ignore,
+    // the request is answered with a 301 to the path of the route
+    Redirect,
+}
+
+/*
+ * Routes requests using a radix tree over the segments of the path. Runs of static segments
+ * are compressed into a single edge, and at every node the children are tried in a fixed order
+ * so that static segments beat captures, captures beat `*` and `*` beats `**` no matter which
+ * route was registered first
+ */
+#[derive(Default)]
+pub(crate) struct Router<'a> {
+    routes: Vec<Route<'a>>,
+    root: Node,
+    // without routes bound to a host every request is found in a single search
+    has_hosts: bool,
+    pub trailing_slash: TrailingSlash,
+}
+
+impl<'a> Router<'a> {
+    pub fn add(&mut self, route: Route<'a>) -> &mut Route<'a> {
+        let idx = self.routes.len();
+        self.has_hosts |= route.host.is_some();
+        if route.invalid.is_none() {
+            // a route with optional captures is reachable both with and without each of them
+            for omitted in 0..=route.optional {
+                let pattern = &route.pattern[..route.pattern.len() - omitted];
+                self.root.insert(pattern).routes.push(idx);
+            }
+        }
+        self.routes.push(route);
+        self.routes.last_mut().unwrap()
+    }
+
+    // in the order they were registered
+    pub fn routes(&self) -> impl Iterator<Item = &Route<'a>> {
+        self.routes.iter()
+    }
+
+    /*
+     * Moves the routes of `other` under `prefix`, `scope` maps the scope of each route to its
+     * scope in the server it is mounted into
+     */
+    pub fn mount(
+        &mut self,
+        prefix: &str,
+        other: Router<'a>,
+        scope: impl Fn(Option<usize>) -> Option<usize>,
+    ) {
+        for route in other.routes {
+            let mut mounted = Route::new(
+                route.methods,
+                format!("{}{}", prefix, route.path),
+                route.handler,
+            );
+            mounted.scope = scope(route.scope);
+            mounted.middlewares = route.middlewares;
+            mounted.name = route.name;
+            mounted.host = route.host;
+            mounted.invalid = mounted.invalid.or(route.invalid);
+            self.add(mounted);
+        }
+    }
+
+    // marks every route as invalid so validation fails with `reason`
+    pub fn invalidate(&mut self, reason: &str) {
+        for route in &mut self.routes {
+            route.invalid = route.invalid.take().or(Some(reason.to_string()));
+        }
+    }
+
+    /*
+     * HEAD requests without a route of their own are answered by the GET route, the body is
+     * left out when the response is written
+     */
+    pub fn find<'r>(&'r self, request: &'r Request) -> Option<(&'r Route<'a>, Params<'r>)> {
+        let path = &request.resource.path;
+        let segments = path.split("/").collect::<Vec<_>>();
+        self.find_method(request, &segments).or_else(|| {
+            if self.trailing_slash != TrailingSlash::Ignore {
+                return None;
+            }
+            let segments = toggle_trailing_slash(path, segments);
+            self.find_method(request, &segments)
+        })
+    }
+
+    /*
+     * The path to redirect to when the path of the request only matches a route with its
+     * trailing slash added or removed and the policy is `TrailingSlash::Redirect`
+     */
+    pub fn trailing_slash_redirect(&self, request: &Request) -> Option<String> {
+        if self.trailing_slash != TrailingSlash::Redirect {
+            return None;
+        }
+        let path = &request.resource.path;
+        let segments = toggle_trailing_slash(path, path.split("/").collect());
+        let location = segments.join("/");
+        // browsers read `//host/...` and `/\host/...` as urls on another site
+        if location.starts_with("//") || location.starts_with("/\\") {
+            return None;
+        }
+        self.find_method(request, &segments)?;
+        Some(format!("{location}{}", request.resource.query_params))
+    }
+
+    fn find_method<'r>(
+        &'r self,
+        request: &'r Request,
+        segments: &[&'r str],
+    ) -> Option<(&'r Route<'a>, Params<'r>)> {
+        let (path, host) = (&request.resource.path, request_host(request));
+        self.find_with(path, host, segments, |route| route.allows(&request.method))
+            .or_else(|| {
+                if request.method != Method::Head {
+                    return None;
+                }
+                self.find_with(path, host, segments, |route| route.allows(&Method::Get))
+            })
+    }
+
+    /*
+     * Routes bound to the host of the request are searched first, so a host's catch-all beats
+     * a more specific route for any host. Routes for any host only serve what none of them match
+     */
+    fn find_with<'r>(
+        &'r self,
+        path: &'r str,
+        host: &'r str,
+        segments: &[&'r str],
+        accept: impl Fn(&Route<'a>) -> bool,
+    ) -> Option<(&'r Route<'a>, Params<'r>)> {
+        let (idx, captured) = self
+            .has_hosts
+            .then(|| {
+                self.search(path, segments, |route| {
+                    route.host.is_some() && route.serves(host) && accept(route)
+                })
+            })
+            .flatten()
+            .or_else(|| {
+                self.search(path, segments, |route| {
+                    route.host.is_none() && accept(route)
+                })
+            })?;
+        let route = &self.routes[idx];
+        let mut params = route.params(&captured);
+        let host_params = route
+            .host
+            .as_ref()
+            .and_then(|pattern| pattern.captures(host));
+        for param in host_params.into_iter().flatten() {
+            params.push(param);
+        }
+        Some((route, params))
+    }
+
+    // the first accepted route in tree order along with what its captures matched
+    fn search<'r>(
+        &'r self,
+        path: &'r str,
+        segments: &[&'r str],
+        accept: impl Fn(&Route<'a>) -> bool,
+    ) -> Option<(usize, Vec<&'r str>)> {
+        let mut found = None;
+        let mut accept_first = |routes: &[usize], captured: &[&'r str]| {
+            let idx = routes.iter().find(|idx| accept(&self.routes[**idx]));
+            if let Some(idx) = idx {
+                found = Some((*idx, captured.to_vec()));
+            }
+            idx.is_some()
+        };
+        self.root
+            .visit(segments, &mut Captured::new(path), &mut accept_first);
+        found
+    }
+
+    /*
+     * Rebuilds the path of the route with the given name, percent-encoding the values of its
+     * captures
+     */
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        let route = self
+            .routes
+            .iter()
+            .find(|route| route.name.as_deref() == Some(name))
+            .ok_or(Error::UrlError(format!("no route named `{name}`")))?;
+        // optional captures are left out starting from the first one without a value
+        let len = (route.pattern.len() - route.optional..route.pattern.len())
+            .find(|idx| match &route.pattern[*idx] {
+                PatternSegment::Capture(capture, _) => {
+                    !params.iter().any(|(key, _)| key == capture)
+                }
+                _ => false,
+            })
+            .unwrap_or(route.pattern.len());
+        let segments =
+            route.pattern[..len]
+                .iter()
+                .map(|seg| match seg {
+                    PatternSegment::Static(s) => Ok(s.clone()),
+                    PatternSegment::Capture(capture, constraint) => {
+                        let (_, value) = params.iter().find(|(key, _)| key == capture).ok_or(
+                            Error::UrlError(format!(
+                                "missing `{capture}` for route `{name}` ({})",
+                                route.path
+                            )),
+                        )?;
+                        if constraint.as_ref().is_some_and(|c| !c.matches(value)) {
+                            return Err(Error::UrlError(format!(
+                                "`{value}` does not fit `{capture}` of route `{name}` ({})",
+                                route.path
+                            )));
+                        }
+                        Ok(percent_encode(value))
+                    }
+                    PatternSegment::Wildcard(Some(wildcard))
+                    | PatternSegment::DoubleWildcard(Some(wildcard)) => {
+                        let (_, value) = params.iter().find(|(key, _)| key == wildcard).ok_or(
+                            Error::UrlError(format!(
+                                "missing `{wildcard}` for route `{name}` ({})",
+                                route.path
+                            )),
+                        )?;
+                        if matches!(seg, PatternSegment::Wildcard(_)) && value.contains('/') {
+                            return Err(Error::UrlError(format!(
+                                "`{value}` spans more than one segment for `{wildcard}` of route `{name}` ({})",
+                                route.path
+                            )));
+                        }
+                        Ok(value
+                            .split('/')
+                            .map(percent_encode)
+                            .collect::<Vec<_>>()
+                            .join("/"))
+                    }
+                    PatternSegment::Wildcard(None) | PatternSegment::DoubleWildcard(None) => {
+                        Err(Error::UrlError(format!(
+                            "route `{name}` ({}) has unnamed wildcards and cannot be built",
+                            route.path
+                        )))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+        Ok(segments.join("/"))
+    }
+
+    /*
+     * Errors on the first pair of routes that would match exactly the same requests, like
+     * `/a/:id` and `/a/:name` for the same method, since only one of them could ever run, and
+     * on routes sharing a name, since `url_for` could only ever build one of them
+     */
+    pub fn validate(&self) -> Result<()> {
+        if let Some(route) = self.routes.iter().find(|route| route.invalid.is_some()) {
+            return Err(Error::InvalidRoute(format!(
+                "{} {}: {}",
+                route.methods_string(),
+                route.location(),
+                route.invalid.as_deref().unwrap_or_default()
+            )));
+        }
+        let mut conflict = None;
+        self.root.walk(&mut |routes| {
+            for (i, a) in routes.iter().enumerate() {
+                for b in routes[i + 1..].iter() {
+                    let (a, b) = (&self.routes[*a], &self.routes[*b]);
+                    if conflict.is_none() && a.overlaps(b) {
+                        conflict = Some(format!(
+                            "{} {} conflicts with {} {}",
+                            a.methods_string(),
+                            a.location(),
+                            b.methods_string(),
+                            b.location()
+                        ));
+                    }
+                }
+            }
+        });
+        if let Some(conflict) = conflict {
+            return Err(Error::RouteConflict(conflict));
+        }
+        for (i, a) in self.routes.iter().enumerate() {
+            let Some(name) = &a.name else {
+                continue;
+            };
+            if let Some(b) = self.routes[i + 1..]
+                .iter()
+                .find(|b| b.name.as_ref() == Some(name))
+            {
+                return Err(Error::RouteConflict(format!(
+                    "{} {} and {} {} are both named `{name}`",
+                    a.methods_string(),
+                    a.location(),
+                    b.methods_string(),
+                    b.location()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * The methods of every route serving the host and path of the request, used for the `Allow`
+     * header. HEAD is allowed wherever GET is and OPTIONS is answered automatically
+     */
+    pub fn allowed_methods(&self, request: &Request) -> Vec<Method> {
+        let (path, host) = (&request.resource.path, request_host(request));
+        let segments = path.split("/").collect::<Vec<_>>();
+        let mut candidates = vec![segments.clone()];
+        if self.trailing_slash == TrailingSlash::Ignore {
+            candidates.push(toggle_trailing_slash(path, segments));
+        }
+        let mut allowed: Vec<Method> = vec![];
+        for segments in candidates {
+            self.root
+                .visit(&segments, &mut Captured::new(path), &mut |routes, _| {
+                    for route in routes
+                        .iter()
+                        .map(|idx| &self.routes[*idx])
+                        .filter(|route| route.serves(host))
+                    {
+                        let methods = if route.methods.is_empty() {
+                            &Method::STANDARD[..]
+                        } else {
+                            &route.methods[..]
+                        };
+                        for method in methods {
+                            if !allowed.contains(method) {
+                                allowed.push(method.clone());
+                            }
+                        }
+                    }
+                    false
+                });
+        }
+        if allowed.is_empty() {
+            return allowed;
+        }
+        if allowed.contains(&Method::Get) && !allowed.contains(&Method::Head) {
+            allowed.push(Method::Head);
+        }
+        if !allowed.contains(&Method::Options) {
+            allowed.push(Method::Options);
+        }
+        // standard methods in a fixed order, then extension methods in registration order
+        allowed.sort_by_key(|method| {
+            Method::STANDARD
+                .iter()
+                .position(|standard| standard == method)
+                .unwrap_or(Method::STANDARD.len())
+        });
+        allowed
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    // the static segments on the edge leading into this node, empty for every other kind
+    prefix: Vec<String>,
+    // keyed by the first segment of the child's prefix
+    statics: HashMap<String, Node>,
+    // constrained captures come first, tried in the order they were registered
+    captures: Vec<Node>,
+    // the constraint on the capture leading into this node
+    constraint: Option<Constraint>,
+    wildcard: Option<Box<Node>>,
+    double_wildcard: Option<Box<Node>>,
+    // indices of the routes whose pattern ends at this node
+    routes: Vec<usize>,
+}
+
+impl Node {
+    fn insert(&mut self, pattern: &[PatternSegment]) -> &mut Node {
+        let Some(seg) = pattern.first() else {
+            return self;
+        };
+        match seg {
+            PatternSegment::Static(_) => {
+                let run = pattern
+                    .iter()
+                    .map_while(|seg| match seg {
+                        PatternSegment::Static(s) => Some(s.clone()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let child = self.statics.entry(run[0].clone()).or_insert_with(|| Node {
+                    prefix: run.clone(),
+                    ..Default::default()
+                });
+                let common = child
+                    .prefix
+                    .iter()
+                    .zip(run.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if common < child.prefix.len() {
+                    child.split(common);
+                }
+                child.insert(&pattern[common..])
+            }
+            PatternSegment::Capture(_, constraint) => {
+                let idx = match self
+                    .captures
+                    .iter()
+                    .position(|child| child.constraint == *constraint)
+                {
+                    Some(idx) => idx,
+                    None => {
+                        let idx = match constraint {
+                            Some(_) => self
+                                .captures
+                                .iter()
+                                .take_while(|child| child.constraint.is_some())
+                                .count(),
+                            None => self.captures.len(),
+                        };
+                        let child = Node {
+                            constraint: constraint.clone(),
+                            ..Default::default()
+                        };
+                        self.captures.insert(idx, child);
+                        idx
+                    }
+                };
+                self.captures[idx].insert(&pattern[1..])
+            }
+            PatternSegment::Wildcard(_) => {
+                self.wildcard.get_or_insert_default().insert(&pattern[1..])
+            }
+            PatternSegment::DoubleWildcard(_) => self
+                .double_wildcard
+                .get_or_insert_default()
+                .insert(&pattern[1..]),
+        }
+    }
+
+    fn walk(&self, f: &mut impl FnMut(&[usize])) {
+        f(&self.routes);
+        let children = self.statics.values().chain(&self.captures).chain(
+            [&self.wildcard, &self.double_wildcard]
+                .into_iter()
+                .flatten()
+                .map(|child| child.as_ref()),
+        );
+        for child in children {
+            child.walk(f);
+        }
+    }
+
+    // moves everything past the first `at` segments of the prefix into a new child
+    fn split(&mut self, at: usize) {
+        let rest = self.prefix.split_off(at);
+        let child = Node {
+            prefix: rest,
+            statics: std::mem::take(&mut self.statics),
+            captures: std::mem::take(&mut self.captures),
+            constraint: None,
+            wildcard: self.wildcard.take(),
+            double_wildcard: self.double_wildcard.take(),
+            routes: std::mem::take(&mut self.routes),
+        };
+        self.statics.insert(child.prefix[0].clone(), child);
+    }
+
+    /*
+     * Walks every node matching all of the segments in priority order, calling `found` with the
+     * routes of each until it returns true
+     */
+    fn visit<'p>(
+        &self,
+        segments: &[&'p str],
+        captured: &mut Captured<'p>,
+        found: &mut impl FnMut(&[usize], &[&'p str]) -> bool,
+    ) -> bool {
+        let matches_prefix = segments.len() >= self.prefix.len()
+            && self.prefix.iter().zip(segments).all(|(a, b)| a == b);
+        matches_prefix && self.visit_children(&segments[self.prefix.len()..], captured, found)
+    }
+
+    fn visit_children<'p>(
+        &self,
+        segments: &[&'p str],
+        captured: &mut Captured<'p>,
+        found: &mut impl FnMut(&[usize], &[&'p str]) -> bool,
+    ) -> bool {
+        let Some((first, rest)) = segments.split_first() else {
+            if !self.routes.is_empty() && found(&self.routes, &captured.values) {
+                return true;
+            }
+            let Some(child) = &self.double_wildcard else {
+                return false;
+            };
+            captured.values.push("");
+            if child.visit(segments, captured, found) {
+                return true;
+            }
+            captured.values.pop();
+            return false;
+        };
+        if let Some(child) = self.statics.get(*first)
+            && child.visit(segments, captured, found)
+        {
+            return true;
+        }
+        // captures and wildcards stand for a value, the empty segment after a trailing slash is
+        // not one
+        for child in &self.captures {
+            if first.is_empty()
+                || child
+                    .constraint
+                    .as_ref()
+                    .is_some_and(|constraint| !constraint.matches(first))
+            {
+                continue;
+            }
+            captured.values.push(first);
+            if child.visit(rest, captured, found) {
+                return true;
+            }
+            captured.values.pop();
+        }
+        if let Some(child) = &self.wildcard
+            && !first.is_empty()
+        {
+            captured.values.push(first);
+            if child.visit(rest, captured, found) {
+                return true;
+            }
+            captured.values.pop();
+        }
+        if let Some(child) = &self.double_wildcard {
+            // consume as few segments as possible so that what follows gets a chance to match
+            for skip in 0..=segments.len() {
+                captured.push_span(&segments[..skip]);
+                if child.visit(&segments[skip..], captured, found) {
+                    return true;
+                }
+                captured.values.pop();
+            }
+        }
+        false
+    }
+}
+
+/*
+ * Adds the trailing slash to a path split into segments when it has none and removes it when it
+ * does, leaving `/` alone. The added segment is an empty slice at the end of the path so
+ * wildcards can still capture their text out of the path
+ */
+fn toggle_trailing_slash<'p>(path: &'p str, mut segments: Vec<&'p str>) -> Vec<&'p str> {
+    if segments.len() > 2 && segments.last() == Some(&"") {
+        segments.pop();
+    } else if path != "/" {
+        segments.push(&path[path.len()..]);
+    }
+    segments
+}
+
+// the values of the captures and wildcards matched so far, in the order of the pattern
+struct Captured<'p> {
+    path: &'p str,
+    values: Vec<&'p str>,
+}
+
+impl<'p> Captured<'p> {
+    fn new(path: &'p str) -> Self {
+        Self {
+            path,
+            values: vec![],
+        }
+    }
+
+    // pushes the text of consecutive segments of the path, slashes included
+    fn push_span(&mut self, segments: &[&'p str]) {
+        let span = match (segments.first(), segments.last()) {
+            (Some(first), Some(last)) => {
+                let start = first.as_ptr() as usize - self.path.as_ptr() as usize;
+                let end = last.as_ptr() as usize + last.len() - self.path.as_ptr() as usize;
+                &self.path[start..end]
+            }
+            _ => "",
+        };
+        self.values.push(span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Headers, Url};
+    use std::sync::Arc;
+
+    fn empty_handler() -> Handler {
+        Arc::new(|c| Ok(c))
+    }
+
+    fn test_request<'a>(method: Method, path: &'a str) -> Request<'a> {
+        Request {
+            method,
+            resource: Url::parse(path).unwrap(),
+            headers: Headers::new(),
+            body: &[],
+        }
+    }
+
+    fn router_with<'a>(routes: &[(Method, &'a str)]) -> Router<'a> {
+        let mut router = Router::default();
+        for (method, pattern) in routes {
+            router.add(Route::new(vec![method.clone()], *pattern, empty_handler()));
+        }
+        router
+    }
+
+    fn single_route(methods: Vec<Method>, pattern: &str) -> Router<'_> {
+        let mut router = Router::default();
+        router.add(Route::new(methods, pattern, empty_handler()));
+        router
+    }
+
+    // the pattern of the route that was found, to tell routes apart
+    fn found_pattern(router: &Router, method: Method, path: &str) -> Option<String> {
+        let request = test_request(method, path);
+        router
+            .find(&request)
+            .map(|(route, _)| route.path.to_string())
+    }
+
+    #[test]
+    fn router_matches_single_slash() {
+        let router = single_route(vec![Method::Get], "/");
+        assert!(router.find(&test_request(Method::Get, "/")).is_some());
+    }
+
+    #[test]
+    fn router_matches_simple_path() {
+        let router = single_route(vec![Method::Get], "/hello/world");
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/hello/world"))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn router_captures_dynamic_path() {
+        let router = single_route(vec![Method::Get], "/:id");
+        let request = test_request(Method::Get, "/1234");
+        let matched = router.find(&request).map(|(_, params)| params);
+        assert!(matched.is_some());
+        assert_eq!(matched.unwrap().get("id"), Some("1234".to_string()));
+    }
+
+    #[test]
+    fn router_captures_multiple_dynamic_paths() {
+        let router = single_route(vec![Method::Get], "/:id/hello/:name");
+        let request = test_request(Method::Get, "/1234/hello/amelia");
+        let matched = router.find(&request).map(|(_, params)| params);
+        assert!(matched.is_some());
+        assert_eq!(matched.clone().unwrap().get("id"), Some("1234".to_string()));
+        assert_eq!(
+            matched.clone().unwrap().get("name"),
+            Some("amelia".to_string())
+        );
+    }
+
+    #[test]
+    fn everything_matches_wildcard() {
+        let router = single_route(vec![Method::Get], "/*");
+        // the root has no segment for the wildcard to stand for
+        assert!(router.find(&test_request(Method::Get, "/")).is_none());
+        assert!(router.find(&test_request(Method::Get, "/hi")).is_some());
+        assert!(router.find(&test_request(Method::Get, "/1234")).is_some());
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/params?key=val&key2=val2"))
+                .is_some()
+        );
+        // a single wildcard matches a single segment, so the whole path has to be consumed
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/trailingslash/"))
+                .is_none()
+        );
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/params/?key=val&key2=val2"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn everything_matches_double_wildcard() {
+        let router = single_route(vec![Method::Get], "/**");
+        assert!(router.find(&test_request(Method::Get, "/")).is_some());
+        assert!(router.find(&test_request(Method::Get, "/hello")).is_some());
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/hello/world"))
+                .is_some()
+        );
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/a/b/c/d/e/f/g/"))
+                .is_some()
+        );
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/hello?key=val&key2=val2"))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn wildcard_matches_with_other_patterns() {
+        let router = single_route(vec![Method::Get], "/hello/*/world");
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/hello/name/world"))
+                .is_some()
+        );
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/hello/12345/world"))
+                .is_some()
+        );
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/hello/world"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn router_matches_listed_methods() {
+        let router = single_route(vec![Method::Put, Method::Patch], "/item");
+        assert!(router.find(&test_request(Method::Put, "/item")).is_some());
+        assert!(router.find(&test_request(Method::Patch, "/item")).is_some());
+        assert!(router.find(&test_request(Method::Get, "/item")).is_none());
+    }
+
+    #[test]
+    fn router_matches_any_method() {
+        let router = single_route(vec![], "/item");
+        assert!(
+            router
+                .find(&test_request(Method::Delete, "/item"))
+                .is_some()
+        );
+        let propfind = Method::Extension("PROPFIND".into());
+        assert!(router.find(&test_request(propfind, "/item")).is_some());
+    }
+
+    #[test]
+    fn static_beats_capture_regardless_of_order() {
+        for routes in [
+            [(Method::Get, "/users/:id"), (Method::Get, "/users/new")],
+            [(Method::Get, "/users/new"), (Method::Get, "/users/:id")],
+        ] {
+            let router = router_with(&routes);
+            assert_eq!(
+                found_pattern(&router, Method::Get, "/users/new").as_deref(),
+                Some("/users/new")
+            );
+            assert_eq!(
+                found_pattern(&router, Method::Get, "/users/42").as_deref(),
+                Some("/users/:id")
+            );
+        }
+    }
+
+    #[test]
+    fn capture_beats_wildcards_regardless_of_order() {
+        let router = router_with(&[
+            (Method::Get, "/files/**"),
+            (Method::Get, "/files/*"),
+            (Method::Get, "/files/:name"),
+        ]);
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/files/a.txt").as_deref(),
+            Some("/files/:name")
+        );
+
+        let router = router_with(&[(Method::Get, "/files/**"), (Method::Get, "/files/*")]);
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/files/a.txt").as_deref(),
+            Some("/files/*")
+        );
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/files/a/b.txt").as_deref(),
+            Some("/files/**")
+        );
+    }
+
+    #[test]
+    fn router_backtracks_out_of_dead_ends() {
+        let router = router_with(&[
+            (Method::Get, "/users/new/settings"),
+            (Method::Get, "/users/:id/profile"),
+        ]);
+        let request = test_request(Method::Get, "/users/new/profile");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("id"), Some("new".to_string()));
+    }
+
+    #[test]
+    fn router_falls_back_to_route_with_matching_method() {
+        let router = router_with(&[(Method::Get, "/users/new"), (Method::Post, "/users/:id")]);
+        assert_eq!(
+            found_pattern(&router, Method::Post, "/users/new").as_deref(),
+            Some("/users/:id")
+        );
+    }
+
+    #[test]
+    fn router_splits_compressed_edges() {
+        let router = router_with(&[
+            (Method::Get, "/api/v1/users"),
+            (Method::Get, "/api/v1/posts"),
+            (Method::Get, "/api/v2"),
+        ]);
+        for path in ["/api/v1/users", "/api/v1/posts", "/api/v2"] {
+            assert_eq!(
+                found_pattern(&router, Method::Get, path).as_deref(),
+                Some(path)
+            );
+        }
+        assert!(found_pattern(&router, Method::Get, "/api/v3").is_none());
+    }
+
+    #[test]
+    fn double_wildcard_stops_at_following_segment() {
+        let router = single_route(vec![Method::Get], "/**/wildcard");
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/a/b/wildcard"))
+                .is_some()
+        );
+        assert!(
+            router
+                .find(&test_request(Method::Get, "/wildcard"))
+                .is_some()
+        );
+        assert!(router.find(&test_request(Method::Get, "/a/b/c")).is_none());
+    }
+
+    #[test]
+    fn duplicate_routes_conflict() {
+        let router = router_with(&[(Method::Get, "/a/b"), (Method::Get, "/a/b")]);
+        let err = router.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Route conflict: GET /a/b conflicts with GET /a/b"
+        );
+    }
+
+    #[test]
+    fn renamed_captures_conflict() {
+        let router = router_with(&[(Method::Get, "/a/:id"), (Method::Get, "/a/:name")]);
+        let err = router.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Route conflict: GET /a/:id conflicts with GET /a/:name"
+        );
+    }
+
+    #[test]
+    fn any_method_conflicts_with_every_method() {
+        let mut router = single_route(vec![Method::Post, Method::Put], "/a/*");
+        router.add(Route::new(vec![], "/a/*", empty_handler()));
+        let err = router.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Route conflict: POST,PUT /a/* conflicts with ANY /a/*"
+        );
+    }
+
+    #[test]
+    fn distinct_routes_do_not_conflict() {
+        let router = router_with(&[
+            (Method::Get, "/a/:id"),
+            (Method::Post, "/a/:name"),
+            (Method::Get, "/a/new"),
+            (Method::Get, "/a/*"),
+            (Method::Get, "/a/**"),
+        ]);
+        assert!(router.validate().is_ok());
+    }
+
+    #[test]
+    fn url_for_rebuilds_named_routes() {
+        let mut router = Router::default();
+        router
+            .add(Route::new(
+                vec![Method::Get],
+                "/users/:id/posts/:post",
+                empty_handler(),
+            ))
+            .name("user.post");
+        let url = router
+            .url_for("user.post", &[("post", "hello world"), ("id", "42")])
+            .unwrap();
+        assert_eq!(url, "/users/42/posts/hello%20world");
+    }
+
+    #[test]
+    fn url_for_errors_on_missing_captures_and_names() {
+        let mut router = Router::default();
+        router
+            .add(Route::new(vec![Method::Get], "/users/:id", empty_handler()))
+            .name("user.show");
+        assert_eq!(
+            router.url_for("user.show", &[]).unwrap_err().to_string(),
+            "Url error missing `id` for route `user.show` (/users/:id)"
+        );
+        assert_eq!(
+            router.url_for("user.edit", &[]).unwrap_err().to_string(),
+            "Url error no route named `user.edit`"
+        );
+    }
+
+    #[test]
+    fn route_names_must_be_unique() {
+        let mut router = Router::default();
+        router
+            .add(Route::new(vec![Method::Get], "/a", empty_handler()))
+            .name("dup");
+        router
+            .add(Route::new(vec![Method::Get], "/b", empty_handler()))
+            .name("dup");
+        assert_eq!(
+            router.validate().unwrap_err().to_string(),
+            "Route conflict: GET /a and GET /b are both named `dup`"
+        );
+    }
+
+    #[test]
+    fn constrained_captures_fall_through_when_they_do_not_fit() {
+        let router = router_with(&[
+            (Method::Get, "/users/:id"),
+            (Method::Get, "/users/:id<u64>"),
+            (Method::Get, r"/files/:name<[a-z]+\.txt>"),
+        ]);
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/users/42"),
+            Some("/users/:id<u64>".into())
+        );
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/users/me"),
+            Some("/users/:id".into())
+        );
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/files/notes.txt"),
+            Some(r"/files/:name<[a-z]+\.txt>".into())
+        );
+        assert_eq!(found_pattern(&router, Method::Get, "/files/notes.md"), None);
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/files/x/notes.txt"),
+            None
+        );
+        let request = test_request(Method::Get, "/users/42");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("id"), Some("42".into()));
+    }
+
+    #[test]
+    fn type_constraints_check_the_range() {
+        let router = single_route(vec![Method::Get], "/level/:n<u8>");
+        assert!(found_pattern(&router, Method::Get, "/level/255").is_some());
+        assert!(found_pattern(&router, Method::Get, "/level/256").is_none());
+        assert!(found_pattern(&router, Method::Get, "/level/-1").is_none());
+    }
+
+    #[test]
+    fn differently_constrained_captures_do_not_conflict() {
+        let router = router_with(&[
+            (Method::Get, "/a/:id<u64>"),
+            (Method::Get, "/a/:slug<[a-z-]+>"),
+            (Method::Get, "/a/:name"),
+        ]);
+        assert!(router.validate().is_ok());
+        let router = router_with(&[(Method::Get, "/a/:id<u64>"), (Method::Get, "/a/:n<u64>")]);
+        assert!(router.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_constraints_fail_validation() {
+        let router = single_route(vec![Method::Get], "/a/:id<[a-z>");
+        assert!(matches!(router.validate(), Err(Error::InvalidRoute(_))));
+        let router = single_route(vec![Method::Get], "/a/:id<u64");
+        assert_eq!(
+            router.validate().unwrap_err().to_string(),
+            "Invalid route: GET /a/:id<u64: `:id<u64` is missing its closing `>`"
+        );
+    }
+
+    #[test]
+    fn url_for_checks_constraints() {
+        let mut router = Router::default();
+        router
+            .add(Route::new(
+                vec![Method::Get],
+                "/users/:id<u64>",
+                empty_handler(),
+            ))
+            .name("user.show");
+        assert_eq!(
+            router.url_for("user.show", &[("id", "7")]).unwrap(),
+            "/users/7"
+        );
+        assert!(router.url_for("user.show", &[("id", "me")]).is_err());
+    }
+
+    #[test]
+    fn wildcards_capture_what_they_match() {
+        let router = router_with(&[
+            (Method::Get, "/static/**path"),
+            (Method::Get, "/users/*id/avatar"),
+            (Method::Get, "/docs/**/edit"),
+        ]);
+        let request = test_request(Method::Get, "/static/css/site/main.css");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("path"), Some("css/site/main.css".into()));
+        assert_eq!(params.get("*"), Some("css/site/main.css".into()));
+
+        let request = test_request(Method::Get, "/users/7/avatar");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("id"), Some("7".into()));
+
+        let request = test_request(Method::Get, "/docs/guide/intro/edit");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("*"), Some("guide/intro".into()));
+    }
+
+    #[test]
+    fn double_wildcard_can_match_nothing() {
+        let router = single_route(vec![Method::Get], "/static/**");
+        let request = test_request(Method::Get, "/static");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("*"), Some("".into()));
+    }
+
+    #[test]
+    fn url_for_fills_named_wildcards() {
+        let mut router = Router::default();
+        router
+            .add(Route::new(
+                vec![Method::Get],
+                "/static/**path",
+                empty_handler(),
+            ))
+            .name("static");
+        router
+            .add(Route::new(vec![Method::Get], "/any/*", empty_handler()))
+            .name("any");
+        assert_eq!(
+            router
+                .url_for("static", &[("path", "css/my site.css")])
+                .unwrap(),
+            "/static/css/my%20site.css"
+        );
+        assert!(router.url_for("any", &[("*", "x")]).is_err());
+    }
+
+    #[test]
+    fn routes_match_whole_paths_only() {
+        let router = router_with(&[(Method::Get, "/hello"), (Method::Get, "/users/:id")]);
+        assert!(found_pattern(&router, Method::Get, "/hello/world").is_none());
+        assert!(found_pattern(&router, Method::Get, "/users/1/posts").is_none());
+        assert!(
+            router
+                .allowed_methods(&test_request(Method::Get, "/hello/world"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn strict_trailing_slash_tells_paths_apart() {
+        let router = router_with(&[(Method::Get, "/hello"), (Method::Get, "/dir/")]);
+        assert!(found_pattern(&router, Method::Get, "/hello").is_some());
+        assert!(found_pattern(&router, Method::Get, "/hello/").is_none());
+        assert!(found_pattern(&router, Method::Get, "/dir/").is_some());
+        assert!(found_pattern(&router, Method::Get, "/dir").is_none());
+        let request = test_request(Method::Get, "/hello/");
+        assert_eq!(router.trailing_slash_redirect(&request), None);
+    }
+
+    #[test]
+    fn ignored_trailing_slash_matches_either_way() {
+        let mut router = router_with(&[
+            (Method::Get, "/hello"),
+            (Method::Get, "/dir/"),
+            (Method::Get, "/files/**"),
+        ]);
+        router.trailing_slash = TrailingSlash::Ignore;
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/hello/"),
+            Some("/hello".into())
+        );
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/dir"),
+            Some("/dir/".into())
+        );
+        assert_eq!(found_pattern(&router, Method::Get, "/"), None);
+        assert!(
+            router
+                .allowed_methods(&test_request(Method::Get, "/hello/"))
+                .contains(&Method::Get)
+        );
+        let request = test_request(Method::Get, "/files/a/b/");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("*"), Some("a/b/".into()));
+    }
+
+    #[test]
+    fn redirected_trailing_slash_points_to_the_route() {
+        let mut router = router_with(&[(Method::Get, "/hello"), (Method::Get, "/dir/")]);
+        router.trailing_slash = TrailingSlash::Redirect;
+        assert!(found_pattern(&router, Method::Get, "/hello/").is_none());
+        let request = test_request(Method::Get, "/hello/?lang=sv");
+        assert_eq!(
+            router.trailing_slash_redirect(&request),
+            Some("/hello?lang=sv".into())
+        );
+        let request = test_request(Method::Get, "/dir");
+        assert_eq!(
+            router.trailing_slash_redirect(&request),
+            Some("/dir/".into())
+        );
+        let request = test_request(Method::Get, "/nowhere/");
+        assert_eq!(router.trailing_slash_redirect(&request), None);
+    }
+
+    #[test]
+    fn captures_do_not_match_the_empty_segment() {
+        let routes = [(Method::Get, "/users"), (Method::Get, "/users/:id")];
+        let mut router = router_with(&routes);
+        assert_eq!(found_pattern(&router, Method::Get, "/users/"), None);
+        let request = test_request(Method::Get, "/users/");
+        assert_eq!(router.trailing_slash_redirect(&request), None);
+
+        router.trailing_slash = TrailingSlash::Ignore;
+        assert_eq!(
+            found_pattern(&router, Method::Get, "/users/"),
+            Some("/users".into())
+        );
+
+        router.trailing_slash = TrailingSlash::Redirect;
+        assert_eq!(found_pattern(&router, Method::Get, "/users/"), None);
+        assert_eq!(
+            router.trailing_slash_redirect(&request),
+            Some("/users".into())
+        );
+
+        let router = router_with(&[(Method::Get, "/files/*")]);
+        assert_eq!(found_pattern(&router, Method::Get, "/files/"), None);
+    }
+
+    #[test]
+    fn optional_captures_do_not_match_the_empty_segment() {
+        let mut router = router_with(&[(Method::Get, "/posts/:id?")]);
+        assert_eq!(found_pattern(&router, Method::Get, "/posts/"), None);
+        router.trailing_slash = TrailingSlash::Ignore;
+        let request = test_request(Method::Get, "/posts/");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("id"), None);
+    }
+
+    #[test]
+    fn redirects_never_lead_to_another_site() {
+        let mut router = router_with(&[(Method::Get, "/**/index.html")]);
+        router.trailing_slash = TrailingSlash::Redirect;
+        let request = test_request(Method::Get, "//evil.com/index.html/");
+        assert_eq!(router.trailing_slash_redirect(&request), None);
+        let request = test_request(Method::Get, "/docs/index.html/");
+        assert_eq!(
+            router.trailing_slash_redirect(&request),
+            Some("/docs/index.html".into())
+        );
+    }
+
+    #[test]
+    fn optional_captures_may_be_left_out() {
+        let router = router_with(&[(Method::Get, "/archive/:year<u16>?/:month?")]);
+        for path in ["/archive", "/archive/2024", "/archive/2024/05"] {
+            assert!(
+                found_pattern(&router, Method::Get, path).is_some(),
+                "{path}"
+            );
+        }
+        assert!(found_pattern(&router, Method::Get, "/archive/latest").is_none());
+        let request = test_request(Method::Get, "/archive/2024");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("year"), Some("2024".into()));
+        assert_eq!(params.get("month"), None);
+    }
+
+    #[test]
+    fn optional_captures_must_come_last() {
+        let router = single_route(vec![Method::Get], "/posts/:id?/edit");
+        assert_eq!(
+            router.validate().unwrap_err().to_string(),
+            "Invalid route: GET /posts/:id?/edit: `edit` follows an optional segment"
+        );
+    }
+
+    #[test]
+    fn optional_captures_conflict_with_the_shorter_route() {
+        let router = router_with(&[(Method::Get, "/posts/:id?"), (Method::Get, "/posts")]);
+        assert!(matches!(router.validate(), Err(Error::RouteConflict(_))));
+    }
+
+    #[test]
+    fn url_for_leaves_out_missing_optional_captures() {
+        let mut router = Router::default();
+        router
+            .add(Route::new(
+                vec![Method::Get],
+                "/posts/:id?",
+                empty_handler(),
+            ))
+            .name("posts");
+        assert_eq!(router.url_for("posts", &[]).unwrap(), "/posts");
+        assert_eq!(router.url_for("posts", &[("id", "3")]).unwrap(), "/posts/3");
+    }
+
+    fn host_request<'a>(host: &'a str, path: &'a str) -> Request<'a> {
+        let mut request = test_request(Method::Get, path);
+        request.headers.set("Host", host);
+        request
+    }
+
+    fn host_router<'a>(routes: &[(Option<&str>, &'a str)]) -> Router<'a> {
+        let mut router = Router::default();
+        for (host, pattern) in routes {
+            let mut route = Route::new(vec![Method::Get], *pattern, empty_handler());
+            if let Some(host) = host {
+                route.set_host(host);
+            }
+            router.add(route);
+        }
+        router
+    }
+
+    fn found_location(router: &Router, request: &Request) -> Option<String> {
+        router.find(request).map(|(route, _)| route.location())
+    }
+
+    #[test]
+    fn host_routes_only_serve_their_host() {
+        let router = host_router(&[
+            (None, "/"),
+            (Some("api.example.com"), "/"),
+            (Some("admin.example.com"), "/users"),
+        ]);
+        let request = host_request("api.example.com", "/");
+        assert_eq!(
+            found_location(&router, &request),
+            Some("api.example.com/".into())
+        );
+        let request = host_request("API.Example.com:8080", "/");
+        assert_eq!(
+            found_location(&router, &request),
+            Some("api.example.com/".into())
+        );
+        let request = host_request("www.example.com", "/");
+        assert_eq!(found_location(&router, &request), Some("/".into()));
+        let request = host_request("api.example.com", "/users");
+        assert_eq!(found_location(&router, &request), None);
+        assert!(router.allowed_methods(&request).is_empty());
+    }
+
+    #[test]
+    fn host_catch_alls_beat_specific_routes_for_any_host() {
+        let router = host_router(&[(Some("admin.example.com"), "/**"), (None, "/generic")]);
+        let request = host_request("admin.example.com", "/generic");
+        assert_eq!(
+            found_location(&router, &request),
+            Some("admin.example.com/**".into())
+        );
+        let request = host_request("www.example.com", "/generic");
+        assert_eq!(found_location(&router, &request), Some("/generic".into()));
+    }
+
+    #[test]
+    fn host_wildcards_and_captures() {
+        let router = host_router(&[
+            (Some("*.static.example.com"), "/**"),
+            (Some(":tenant.example.com"), "/dashboard"),
+        ]);
+        let request = host_request("eu.static.example.com", "/app.js");
+        assert!(router.find(&request).is_some());
+        let request = host_request("static.example.com", "/app.js");
+        assert!(router.find(&request).is_none());
+
+        let request = host_request("acme.example.com", "/dashboard");
+        let (_, params) = router.find(&request).unwrap();
+        assert_eq!(params.get("tenant"), Some("acme".into()));
+        let request = host_request("a.b.example.com", "/dashboard");
+        assert!(router.find(&request).is_none());
+    }
+
+    #[test]
+    fn percent_signs_never_match_a_host() {
+        let router = host_router(&[(Some(":tenant.example.com"), "/"), (None, "/")]);
+        let request = host_request("%zz.example.com", "/");
+        let (route, params) = router.find(&request).unwrap();
+        assert_eq!(route.location(), "/");
+        assert!(params.percent_decoded().is_ok());
+    }
+
+    #[test]
+    fn absolute_urls_take_precedence_over_the_host_header() {
+        let router = host_router(&[(Some("api.example.com"), "/")]);
+        let mut request = host_request("www.example.com", "/");
+        request.resource = Url::parse("http://api.example.com/").unwrap();
+        assert!(router.find(&request).is_some());
+    }
+
+    #[test]
+    fn routes_on_different_hosts_do_not_conflict() {
+        let router = host_router(&[
+            (None, "/"),
+            (Some("a.example.com"), "/"),
+            (Some("b.example.com"), "/"),
+        ]);
+        assert!(router.validate().is_ok());
+        let router = host_router(&[(Some("a.example.com"), "/"), (Some("a.example.com"), "/")]);
+        assert_eq!(
+            router.validate().unwrap_err().to_string(),
+            "Route conflict: GET a.example.com/ conflicts with GET a.example.com/"
+        );
+        let router = host_router(&[(Some("**.example.com"), "/")]);
+        assert!(matches!(router.validate(), Err(Error::InvalidRoute(_))));
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/*
+ * Stops a running server from another thread. The server stops accepting connections, lets
+ * requests that are already being handled finish and closes connections that are waiting for
+ * their next request
+ */
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    requested: AtomicBool,
+    closed: AtomicBool,
+    next_id: AtomicUsize,
+    listener: Mutex<Option<SocketAddr>>,
+    connections: Mutex<HashMap<usize, Tracked>>,
+}
+
+struct Tracked {
+    stream: TcpStream,
+    busy: bool,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        for tracked in self.state.connections.lock().unwrap().values() {
+            if !tracked.busy {
+                let _ = tracked.stream.shutdown(Shutdown::Read);
+            }
+        }
+        // wakes up the blocking accept so it sees the shutdown
+        if let Some(addr) = *self.state.listener.lock().unwrap() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    /*
+     * Remembers where the server is listening so a shutdown can wake up its accept loop
+     */
+    pub(crate) fn listen_on(&self, listener: &TcpListener) -> std::io::Result<()> {
+        let mut addr = listener.local_addr()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        *self.state.listener.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    pub(crate) fn has_connections(&self) -> bool {
+        !self.state.connections.lock().unwrap().is_empty()
+    }
+
+    /*
+     * Forcibly closes whatever is still open once the shutdown deadline has passed
+     */
+    pub(crate) fn close_all(&self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        for tracked in self.state.connections.lock().unwrap().values() {
+            let _ = tracked.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub(crate) fn track(&self, stream: &TcpStream) -> std::io::Result<TrackedConnection<'_>> {
+        let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        let stream = stream.try_clone()?;
+        if self.state.closed.load(Ordering::SeqCst) {
+            let _ = stream.shutdown(Shutdown::Both);
+        } else if self.is_shutdown() {
+            // accepted before the shutdown but picked up by a worker after it, so still idle
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        self.state.connections.lock().unwrap().insert(
+            id,
+            Tracked {
+                stream,
+                busy: false,
+            },
+        );
+        Ok(TrackedConnection { handle: self, id })
+    }
+}
+
+/*
+ * Keeps a connection registered with its `ShutdownHandle` until it is dropped
+ */
+pub(crate) struct TrackedConnection<'a> {
+    handle: &'a ShutdownHandle,
+    id: usize,
+}
+
+impl TrackedConnection<'_> {
+    pub fn set_busy(&self, busy: bool) {
+        if let Some(tracked) = self
+            .handle
+            .state
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(&self.id)
+        {
+            tracked.busy = busy;
+        }
+    }
+}
+
+impl Drop for TrackedConnection<'_> {
+    fn drop(&mut self) {
+        self.handle
+            .state
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}